@@ -0,0 +1 @@
+ALTER TABLE cache ADD COLUMN generated_query TEXT;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_cache_created_at;
+DROP INDEX IF EXISTS idx_cache_expires_at;
+ALTER TABLE cache DROP COLUMN expires_at;
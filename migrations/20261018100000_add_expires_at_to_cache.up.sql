@@ -0,0 +1,3 @@
+ALTER TABLE cache ADD COLUMN expires_at TIMESTAMP;
+CREATE INDEX IF NOT EXISTS idx_cache_expires_at ON cache (expires_at);
+CREATE INDEX IF NOT EXISTS idx_cache_created_at ON cache (created_at);
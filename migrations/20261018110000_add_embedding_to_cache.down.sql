@@ -0,0 +1 @@
+ALTER TABLE cache DROP COLUMN embedding;
@@ -0,0 +1 @@
+ALTER TABLE cache ADD COLUMN embedding BLOB;
@@ -0,0 +1,4 @@
+ALTER TABLE cache DROP COLUMN prompt_version;
+ALTER TABLE cache DROP COLUMN llm_model;
+ALTER TABLE cache DROP COLUMN options;
+ALTER TABLE cache DROP COLUMN routed_engine;
@@ -0,0 +1,4 @@
+ALTER TABLE cache ADD COLUMN routed_engine TEXT;
+ALTER TABLE cache ADD COLUMN options TEXT;
+ALTER TABLE cache ADD COLUMN llm_model TEXT;
+ALTER TABLE cache ADD COLUMN prompt_version TEXT;
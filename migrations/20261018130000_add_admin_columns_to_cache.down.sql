@@ -0,0 +1,3 @@
+ALTER TABLE cache DROP COLUMN pinned;
+ALTER TABLE cache DROP COLUMN last_used_at;
+ALTER TABLE cache DROP COLUMN hit_count;
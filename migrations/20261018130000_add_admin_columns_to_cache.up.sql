@@ -0,0 +1,3 @@
+ALTER TABLE cache ADD COLUMN hit_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE cache ADD COLUMN last_used_at TIMESTAMP;
+ALTER TABLE cache ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN cached_request_count;
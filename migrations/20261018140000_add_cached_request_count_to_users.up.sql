@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN cached_request_count INTEGER NOT NULL DEFAULT 0;
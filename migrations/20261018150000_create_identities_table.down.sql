@@ -0,0 +1,13 @@
+DROP TABLE IF EXISTS identities;
+
+CREATE TABLE users_old (
+    id BIGINT PRIMARY KEY,
+    username TEXT NOT NULL,
+    request_count INTEGER NOT NULL DEFAULT 0,
+    last_request_date DATE NOT NULL,
+    cached_request_count INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO users_old (id, username, request_count, last_request_date, cached_request_count)
+    SELECT id, username, request_count, last_request_date, cached_request_count FROM users;
+DROP TABLE users;
+ALTER TABLE users_old RENAME TO users;
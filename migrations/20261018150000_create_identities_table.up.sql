@@ -0,0 +1,26 @@
+-- Users get their own ids, provider accounts are linked to them through identities
+CREATE TABLE users_new (
+    id INTEGER PRIMARY KEY,
+    username TEXT NOT NULL,
+    request_count INTEGER NOT NULL DEFAULT 0,
+    last_request_date DATE NOT NULL,
+    cached_request_count INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO users_new (id, username, request_count, last_request_date, cached_request_count)
+    SELECT id, username, request_count, last_request_date, cached_request_count FROM users;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+CREATE TABLE IF NOT EXISTS identities (
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (provider, subject)
+);
+CREATE INDEX IF NOT EXISTS identities_user_id ON identities (user_id);
+
+-- Existing users signed in with Discord and were keyed by their Discord id
+INSERT INTO identities (provider, subject, user_id, username)
+    SELECT 'discord', CAST(id AS TEXT), id, username FROM users;
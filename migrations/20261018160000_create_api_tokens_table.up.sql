@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    -- SHA-256 of the token, the token itself is only shown once
+    token_hash TEXT NOT NULL UNIQUE,
+    -- Start of the token, to tell tokens apart
+    prefix TEXT NOT NULL,
+    -- Comma separated, NULL grants every scope
+    scopes TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP,
+    last_used_at TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS api_tokens_user_id ON api_tokens (user_id);
@@ -0,0 +1,7 @@
+-- Daily usage of unauthenticated clients, keyed by IP address or subnet
+CREATE TABLE IF NOT EXISTS guest_quotas (
+    client TEXT PRIMARY KEY,
+    request_count INTEGER NOT NULL DEFAULT 0,
+    cached_request_count INTEGER NOT NULL DEFAULT 0,
+    last_request_date DATE NOT NULL
+);
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS login_allowlist;
+ALTER TABLE users DROP COLUMN banned;
+ALTER TABLE users DROP COLUMN role;
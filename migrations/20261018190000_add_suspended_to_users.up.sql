@@ -0,0 +1,2 @@
+-- Set while a user fails the Discord guild membership check, lifted automatically when they pass again
+ALTER TABLE users ADD COLUMN suspended BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    -- SHA-256 of the session key in the cookie
+    key_hash TEXT PRIMARY KEY,
+    -- Shown to the user to tell sessions apart, set on sign-in
+    session_id TEXT,
+    user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+    user_agent TEXT,
+    -- JSON object of the session entries
+    state TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    last_seen_at TIMESTAMP NOT NULL,
+    -- The session ends at the latest here, however active it is
+    max_expires_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions (user_id);
+CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions (expires_at);
@@ -0,0 +1,9 @@
+-- Username and password logins, the account also has a `local` identity with the lowercase username as subject
+CREATE TABLE IF NOT EXISTS local_accounts (
+    user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    username TEXT NOT NULL UNIQUE,
+    -- Argon2 PHC string
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
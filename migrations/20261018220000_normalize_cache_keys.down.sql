@@ -0,0 +1,2 @@
+-- Normalized keys can't be mapped back to the original ones, entries keep their new keys
+SELECT 1;
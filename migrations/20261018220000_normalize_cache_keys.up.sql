@@ -0,0 +1,36 @@
+-- Entries cached before keys were normalized are re-keyed, so lookups find them again.
+-- SQLite only lowercases ASCII letters, prompts with other upper case or NFKC forms stay unreachable.
+CREATE TEMP TABLE cache_keys AS
+    SELECT
+        rowid AS id,
+        replace(replace(replace(replace(replace(replace(lower(trim(replace(replace(replace(query_prompt, char(9), ' '), char(10), ' '), char(13), ' '))), '  ', ' '), '  ', ' '), '  ', ' '), '  ', ' '), '  ', ' '), '  ', ' ') AS query_prompt,
+        CASE lower(trim(search_engine))
+            WHEN 'ddg' THEN 'duckduckgo'
+            WHEN 'ddg-lite' THEN 'duckduckgo'
+            WHEN 'ddg-html' THEN 'duckduckgo'
+            WHEN 'ddg-noai' THEN 'duckduckgo'
+            WHEN 'duckduckgo-lite' THEN 'duckduckgo'
+            WHEN 'duckduckgo-html' THEN 'duckduckgo'
+            WHEN 'duckduckgo-noai' THEN 'duckduckgo'
+            ELSE lower(trim(search_engine))
+        END AS search_engine,
+        lower(trim(language)) AS language
+    FROM cache;
+
+-- Entries whose normalized key is taken are left as they are, and removed below
+UPDATE OR IGNORE cache
+SET query_prompt = cache_keys.query_prompt, search_engine = cache_keys.search_engine, language = cache_keys.language
+FROM cache_keys
+WHERE cache.rowid = cache_keys.id
+  AND (cache.query_prompt != cache_keys.query_prompt
+    OR cache.search_engine != cache_keys.search_engine
+    OR cache.language != cache_keys.language);
+
+DELETE FROM cache WHERE rowid IN (
+    SELECT cache_keys.id FROM cache_keys JOIN cache ON cache.rowid = cache_keys.id
+    WHERE cache.query_prompt != cache_keys.query_prompt
+       OR cache.search_engine != cache_keys.search_engine
+       OR cache.language != cache_keys.language
+);
+
+DROP TABLE cache_keys;
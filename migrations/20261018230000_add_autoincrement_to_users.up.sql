@@ -0,0 +1,32 @@
+-- Ids of deleted users must not be handed out again, configured roles and logs may still name them.
+-- Migrations run in a transaction with foreign keys enforced, so dropping the old table deletes the rows referencing
+-- it. They are copied aside and restored once the new table has taken its place.
+CREATE TABLE users_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    request_count INTEGER NOT NULL DEFAULT 0,
+    last_request_date DATE NOT NULL,
+    cached_request_count INTEGER NOT NULL DEFAULT 0,
+    role TEXT NOT NULL DEFAULT 'user',
+    banned BOOLEAN NOT NULL DEFAULT FALSE,
+    suspended BOOLEAN NOT NULL DEFAULT FALSE
+);
+INSERT INTO users_new (id, username, request_count, last_request_date, cached_request_count, role, banned, suspended)
+    SELECT id, username, request_count, last_request_date, cached_request_count, role, banned, suspended FROM users;
+
+CREATE TEMP TABLE identities_backup AS SELECT * FROM identities;
+CREATE TEMP TABLE api_tokens_backup AS SELECT * FROM api_tokens;
+CREATE TEMP TABLE sessions_backup AS SELECT * FROM sessions;
+CREATE TEMP TABLE local_accounts_backup AS SELECT * FROM local_accounts;
+
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
+
+INSERT INTO identities SELECT * FROM identities_backup;
+INSERT INTO api_tokens SELECT * FROM api_tokens_backup;
+INSERT INTO sessions SELECT * FROM sessions_backup;
+INSERT INTO local_accounts SELECT * FROM local_accounts_backup;
+DROP TABLE identities_backup;
+DROP TABLE api_tokens_backup;
+DROP TABLE sessions_backup;
+DROP TABLE local_accounts_backup;
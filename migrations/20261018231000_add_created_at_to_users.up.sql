@@ -0,0 +1,2 @@
+-- Set on signup. Existing users signed up before it was recorded and are never treated as new accounts.
+ALTER TABLE users ADD COLUMN created_at TIMESTAMP;
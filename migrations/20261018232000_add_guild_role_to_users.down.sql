@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN guild_role;
@@ -0,0 +1,3 @@
+-- Role granted by the Discord guild role mapping, taken back when the user loses the guild role.
+-- Roles mapped before this was recorded count as set by hand.
+ALTER TABLE users ADD COLUMN guild_role TEXT;
@@ -0,0 +1,337 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    num::NonZeroU32,
+    path::PathBuf,
+};
+
+use clap::{Parser, Subcommand};
+use governor::{Quota, RateLimiter};
+
+use crate::{
+    model::{search::SearchOptions, UserQueryRequest},
+    service::{
+        cache::{CacheFilter, CacheRecord, QueryCache},
+        search::{cache_engine_key, SearchError, SearchService},
+        user::{Role, UserService},
+    },
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "bangs", about = "AI search bangs, starts the web service without a command")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Administrate the query cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Manage users, roles and the login allowlist
+    #[command(subcommand)]
+    User(UserCommand),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct FilterArgs {
+    /// SQL `LIKE` pattern on the normalized prompt, e.g. `%vim%`
+    #[arg(long)]
+    pattern: Option<String>,
+    /// Cache engine, e.g. `google` or `auto:dev`
+    #[arg(long)]
+    engine: Option<String>,
+    /// Only entries created more than this many seconds ago
+    #[arg(long)]
+    older_than: Option<i64>,
+}
+
+impl FilterArgs {
+    fn into_filter(self, include_pinned: bool) -> CacheFilter {
+        CacheFilter {
+            prompt_pattern: self.pattern,
+            engine: self.engine,
+            older_than_seconds: self.older_than,
+            include_pinned,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    #[command(flatten)]
+    Admin(CacheAdminCommand),
+    /// Generate queries for a JSONL file of `{ "engine", "prompt", "language", "vertical" }` requests
+    Warm(WarmArgs),
+}
+
+/// Cache commands that only need the database.
+#[derive(Subcommand, Debug)]
+pub enum CacheAdminCommand {
+    /// List cached queries, most recent first
+    List {
+        #[command(flatten)]
+        filter: FilterArgs,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+    },
+    /// Delete cached queries matching the filter
+    Invalidate {
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Also delete pinned entries
+        #[arg(long)]
+        include_pinned: bool,
+    },
+    /// Keep an entry regardless of its TTL
+    Pin { id: i64 },
+    /// Let an entry expire again
+    Unpin { id: i64 },
+    /// Replace the generated query of an entry
+    Edit { id: i64, query: String },
+    /// Remove expired entries and trim the cache to its maximum size
+    Purge,
+    /// Write every entry as JSONL
+    Export {
+        /// Output file, stdout if unset
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Read entries written by `export`
+    Import {
+        file: PathBuf,
+        /// Replace entries that are already cached
+        #[arg(long)]
+        overwrite: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommand {
+    /// List users with their role
+    List,
+    /// Set the role of a user: user, power or admin
+    Role { id: i64, role: Role },
+    /// Block a user from searching and signing in
+    Ban { id: i64 },
+    Unban { id: i64 },
+    /// Let an identity sign up in allowlist mode, e.g. `discord:80351110224678912`
+    Allow { identity: String },
+    /// Remove an identity from the allowlist, linked accounts stay signed up
+    Disallow { identity: String },
+    /// List identities on the allowlist
+    Allowlist,
+    /// Delete a user with their identities, tokens and sessions
+    Delete { id: i64 },
+}
+
+#[derive(clap::Args, Debug)]
+pub struct WarmArgs {
+    file: PathBuf,
+    /// Maximum number of LLM calls per minute
+    #[arg(long, default_value_t = 30)]
+    per_minute: u32,
+}
+
+pub async fn run_cache_command(
+    cache: &QueryCache,
+    searxng_instances: &[(String, String)],
+    command: CacheAdminCommand,
+) -> anyhow::Result<()> {
+    match command {
+        CacheAdminCommand::List { filter, limit, offset } => {
+            let entries = cache.list(&filter.into_filter(true), limit, offset).await?;
+            for entry in entries {
+                println!(
+                    "{}\t{}\t{} hits\tlast used {}\t{}\t{}\t{} -> {}",
+                    entry.id,
+                    if entry.pinned { "pinned" } else { "-" },
+                    entry.hit_count,
+                    entry
+                        .last_used_at
+                        .map(|last_used_at| last_used_at.format("%F %T").to_string())
+                        .unwrap_or("never".to_string()),
+                    entry.search_engine,
+                    entry.language,
+                    entry.query_prompt,
+                    entry.generated_query.unwrap_or(entry.url),
+                );
+            }
+        }
+        CacheAdminCommand::Invalidate { filter, include_pinned } => {
+            let removed = cache.invalidate(&filter.into_filter(include_pinned)).await?;
+            println!("Removed {removed} entries");
+        }
+        CacheAdminCommand::Pin { id } => report(id, cache.set_pinned(id, true).await?)?,
+        CacheAdminCommand::Unpin { id } => report(id, cache.set_pinned(id, false).await?)?,
+        CacheAdminCommand::Edit { id, query } => report(id, cache.edit_query(id, &query).await?)?,
+        CacheAdminCommand::Purge => {
+            let removed = cache.purge().await?;
+            println!("Removed {removed} entries");
+        }
+        CacheAdminCommand::Export { output } => {
+            let mut writer: Box<dyn Write> = match output {
+                Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            for record in cache.export().await? {
+                writeln!(writer, "{}", serde_json::to_string(&record)?)?;
+            }
+            writer.flush()?;
+        }
+        CacheAdminCommand::Import { file, overwrite } => {
+            let (mut imported, mut skipped) = (0, 0);
+            for (number, line) in BufReader::new(File::open(file)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: CacheRecord = serde_json::from_str(&line)
+                    .map_err(|e| anyhow::anyhow!("Invalid entry on line {}: {}", number + 1, e))?;
+                let search_engine = cache_engine_key(&record.search_engine, searxng_instances)
+                    .map_err(|e| anyhow::anyhow!("Invalid entry on line {}: {}", number + 1, e))?;
+                if cache.import(&record, &search_engine, overwrite).await? {
+                    imported += 1;
+                } else {
+                    skipped += 1;
+                }
+            }
+            println!("Imported {imported} entries, skipped {skipped} already cached or expired");
+        }
+    }
+    Ok(())
+}
+
+/// Run every request of the file through the search service, at most `per_minute` LLM calls a minute.
+/// Failed requests are logged and skipped.
+pub async fn warm_cache(search_service: &dyn SearchService, args: WarmArgs) -> anyhow::Result<()> {
+    let per_minute = NonZeroU32::new(args.per_minute).ok_or(anyhow::anyhow!("--per-minute must be positive"))?;
+    let limiter = RateLimiter::direct(Quota::per_minute(per_minute));
+
+    let (mut generated, mut cached, mut failed) = (0, 0, 0);
+    for (number, line) in BufReader::new(File::open(args.file)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: UserQueryRequest = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                log::error!("Invalid request on line {}: {}", number + 1, e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        // Only LLM calls are rate limited, cached requests are answered right away
+        let mut result = Err(SearchError::NotCached);
+        for allow_generation in [false, true] {
+            if allow_generation {
+                limiter.until_ready().await;
+            }
+            result = search_service
+                .generate_query(
+                    &request.prompt,
+                    &request.engine,
+                    &request.language,
+                    request.vertical.as_deref(),
+                    &SearchOptions::default(),
+                    allow_generation,
+                )
+                .await;
+            if !matches!(result, Err(SearchError::NotCached)) {
+                break;
+            }
+        }
+        match result {
+            Ok(result) if result.from_cache => cached += 1,
+            Ok(result) => {
+                println!("{}\t{}", request.prompt, result.url);
+                generated += 1;
+            }
+            Err(e) => {
+                log::error!("Failed to generate query for line {}: {}", number + 1, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Generated {generated} queries, {cached} already cached, {failed} failed");
+    Ok(())
+}
+
+fn report(id: i64, found: bool) -> anyhow::Result<()> {
+    if !found {
+        anyhow::bail!("No cache entry with id {id}");
+    }
+    println!("Updated entry {id}");
+    Ok(())
+}
+
+pub async fn run_user_command(users: &UserService, command: UserCommand) -> anyhow::Result<()> {
+    match command {
+        UserCommand::List => {
+            for user in users.list().await? {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    user.id,
+                    user.role,
+                    match (user.banned, user.suspended) {
+                        (true, _) => "banned",
+                        (false, true) => "suspended",
+                        (false, false) => "-",
+                    },
+                    user.username
+                );
+            }
+        }
+        UserCommand::Role { id, role } => {
+            if let Some(configured) = users.configured_role(id) {
+                anyhow::bail!("User {id} has the {configured} role from POWER_USER_IDS or ADMIN_USER_IDS, change it there");
+            }
+            report_user(id, users.set_role(id, role).await?)?
+        }
+        UserCommand::Ban { id } => report_user(id, users.set_banned(id, true).await?)?,
+        UserCommand::Unban { id } => report_user(id, users.set_banned(id, false).await?)?,
+        UserCommand::Allow { identity } => {
+            let (provider, subject) = parse_identity(&identity)?;
+            users.allow(provider, subject).await?;
+            println!("Allowed {identity}");
+        }
+        UserCommand::Disallow { identity } => {
+            let (provider, subject) = parse_identity(&identity)?;
+            if !users.disallow(provider, subject).await? {
+                anyhow::bail!("{identity} is not on the allowlist");
+            }
+            println!("Removed {identity}");
+        }
+        UserCommand::Allowlist => {
+            for (provider, subject) in users.allowlist().await? {
+                println!("{provider}:{subject}");
+            }
+        }
+        UserCommand::Delete { id } => {
+            if !users.delete(id).await? {
+                anyhow::bail!("No user with id {id}");
+            }
+            println!("Deleted user {id}");
+        }
+    }
+    Ok(())
+}
+
+fn parse_identity(identity: &str) -> anyhow::Result<(&str, &str)> {
+    identity
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Identities look like `provider:subject`"))
+}
+
+fn report_user(id: i64, found: bool) -> anyhow::Result<()> {
+    if !found {
+        anyhow::bail!("No user with id {id}");
+    }
+    println!("Updated user {id}");
+    Ok(())
+}
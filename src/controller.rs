@@ -1,2 +1,45 @@
 pub mod search;
 pub mod auth;
+pub mod cache;
+pub mod local_account;
+pub mod page;
+pub mod token;
+
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::service::captcha::CaptchaService;
+
+/// Address of the client. Only trust `Forwarded` or `X-Forwarded-For` behind a reverse proxy.
+pub fn client_ip(req: &HttpRequest, trust_forwarded_for: bool) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let connection_info = req.connection_info();
+        let address = connection_info.realip_remote_addr()?;
+        // Without a proxy header this is the peer address, including its port
+        address
+            .parse()
+            .ok()
+            .or_else(|| address.parse::<SocketAddr>().ok().map(|address| address.ip()))
+    } else {
+        Some(req.peer_addr()?.ip())
+    }
+}
+
+/// Check the captcha token of a request, the error is the response to send.
+pub async fn verify_captcha(
+    req: &HttpRequest,
+    captcha_service: &CaptchaService,
+    token: &str,
+    action: &str,
+) -> Result<(), HttpResponse> {
+    let remote_ip = client_ip(req, captcha_service.trust_forwarded_for());
+    match captcha_service.verify(token, remote_ip, action).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HttpResponse::BadRequest().body("Captcha verification failed, please try again")),
+        Err(e) => {
+            log::error!("Failed to verify captcha token: {}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
@@ -1,111 +1,252 @@
-use std::{env, sync::Arc};
+use std::sync::Arc;
 
-use actix_session::Session;
-use actix_web::{get, web, HttpResponse, Responder, Scope};
+use actix_session::{Session, SessionInsertError};
+use actix_web::{delete, get, http::header, web, HttpRequest, HttpResponse, Responder, Scope};
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
 
-use crate::service::{auth::AuthService, turnstile::TurnstileService};
+use crate::{
+    controller::{self, local_account, token},
+    service::{
+        captcha::CaptchaService,
+        auth::{AuthError, AuthService, LoginAttempt, LoginAttemptError, ProviderInfo},
+        guild::{GuildAccess, GuildGate},
+        local_account::LocalAccountService,
+        quota::{QuotaService, QuotaSubject},
+        risk::CHALLENGE_PASSED_AT,
+        session::{self, SqliteSessionStore},
+        token::{TokenScope, TokenService},
+        user::UserService,
+    },
+};
 
 #[derive(Serialize)]
 struct ConfigResponse {
-    turnstile_site_key: String,
+    captcha: CaptchaConfig,
+    providers: Vec<ProviderInfo>,
+    /// Whether username and password accounts are enabled
+    local_accounts: bool,
+    /// Whether registration needs an invite code
+    invite_code_required: bool,
+}
+
+/// Which captcha widget the pages render.
+#[derive(Serialize)]
+struct CaptchaConfig {
+    provider: &'static str,
+    site_key: String,
 }
 
 #[derive(Deserialize)]
 pub struct AuthQuery {
-    #[serde(rename = "cf-turnstile-response")]
-    turnstile_response: String,
+    #[serde(rename = "captcha-response", default)]
+    captcha_response: String,
+}
+
+/// A solved captcha and the page to continue to.
+#[derive(Deserialize)]
+pub struct VerifyQuery {
+    #[serde(rename = "captcha-response", default)]
+    captcha_response: String,
+    next: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct CallbackQuery {
-    code: String,
+    code: Option<String>,
+    state: Option<String>,
+    /// Set by the provider when the user denied access
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    /// Must repeat the username
+    confirm: String,
 }
 
 pub fn service() -> Scope {
     web::scope("/auth")
         .service(config)
         .service(me)
-        .service(discord_login)
-        .service(discord_callback)
         .service(logout)
+        .service(guest)
+        .service(challenge)
+        .service(list_sessions)
+        .service(revoke_sessions)
+        .service(revoke_session)
+        .service(export_account)
+        .service(delete_account)
+        .service(local_account::service())
+        .service(provider_login)
+        .service(provider_callback)
 }
 
 #[get("/config")]
-async fn config() -> impl Responder {
-    let site_key = env::var("CLOUDFLARE_TURNSTILE_SITE_KEY")
-        .expect("CLOUDFLARE_TURNSTILE_SITE_KEY must be set");
+async fn config(
+    auth_service: web::Data<Arc<dyn AuthService>>,
+    captcha_service: web::Data<Arc<CaptchaService>>,
+    local_account_service: web::Data<Option<Arc<LocalAccountService>>>,
+) -> impl Responder {
     HttpResponse::Ok().json(ConfigResponse {
-        turnstile_site_key: site_key,
+        captcha: CaptchaConfig {
+            provider: captcha_service.provider(),
+            site_key: captcha_service.site_key().to_string(),
+        },
+        providers: auth_service.providers(),
+        local_accounts: local_account_service.is_some(),
+        invite_code_required: local_account_service
+            .as_deref()
+            .is_some_and(LocalAccountService::requires_invite_code),
     })
 }
 
 #[get("/me")]
-async fn me(session: Session, pool: web::Data<SqlitePool>) -> impl Responder {
-    match session.get::<i64>("user_id") {
+async fn me(
+    req: HttpRequest,
+    session: Session,
+    auth_service: web::Data<Arc<dyn AuthService>>,
+    quota_service: web::Data<Arc<QuotaService>>,
+    token_service: web::Data<Arc<TokenService>>,
+    user_service: web::Data<Arc<UserService>>,
+) -> impl Responder {
+    match token::request_user(&req, &session, &token_service, TokenScope::Account).await {
         Ok(Some(user_id)) => {
-            match sqlx::query!("SELECT username FROM users WHERE id = ?", user_id)
-                .fetch_one(pool.get_ref())
-                .await
-            {
-                Ok(user) => HttpResponse::Ok().json(serde_json::json!({ "username": user.username })),
-                Err(_) => HttpResponse::InternalServerError().finish(),
+            match (
+                user_service.get(user_id).await,
+                auth_service.identities(user_id).await,
+                quota_service.usage(&QuotaSubject::User(user_id)).await,
+            ) {
+                (Ok(Some(user)), Ok(identities), Ok(quota)) => match user.blocked_reason() {
+                    Some(reason) => HttpResponse::Forbidden().body(reason),
+                    None => HttpResponse::Ok().json(serde_json::json!({
+                        "username": user.username,
+                        "role": user.role,
+                        "identities": identities,
+                        "quota": quota,
+                    })),
+                },
+                (Ok(None), _, _) => HttpResponse::Unauthorized().finish(),
+                _ => HttpResponse::InternalServerError().finish(),
             }
         }
         _ => HttpResponse::Unauthorized().finish(),
     }
 }
 
-#[get("/discord/login")]
-async fn discord_login(
+/// Let a guest search after solving the captcha.
+#[get("/guest")]
+async fn guest(
+    req: HttpRequest,
+    query: web::Query<VerifyQuery>,
+    captcha_service: web::Data<Arc<CaptchaService>>,
+    session: Session,
+) -> impl Responder {
+    if let Err(response) = controller::verify_captcha(&req, &captcha_service, &query.captcha_response, "guest").await {
+        return response;
+    }
+    if session.insert("guest_verified", true).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    redirect_next(query.next.as_deref())
+}
+
+/// Let a search through that looked automated, see `RiskScorer`.
+#[get("/challenge")]
+async fn challenge(
+    req: HttpRequest,
+    query: web::Query<VerifyQuery>,
+    captcha_service: web::Data<Arc<CaptchaService>>,
+    session: Session,
+) -> impl Responder {
+    if let Err(response) =
+        controller::verify_captcha(&req, &captcha_service, &query.captcha_response, "challenge").await
+    {
+        return response;
+    }
+    if session.insert(CHALLENGE_PASSED_AT, chrono::Utc::now().timestamp()).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    redirect_next(query.next.as_deref())
+}
+
+/// Whether `next` is a path on this site. Browsers treat `\` like `/` and drop tabs and newlines, so `/\evil.example`
+/// or `/\t/evil.example` would lead to another host.
+fn is_local_path(next: &str) -> bool {
+    next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.contains('\\')
+        && !next.chars().any(|c| c.is_ascii_control())
+}
+
+fn redirect_next(next: Option<&str>) -> HttpResponse {
+    let next = next.filter(|next| is_local_path(next)).unwrap_or("/");
+    HttpResponse::Found()
+        .append_header(("Location", next))
+        .finish()
+}
+
+#[get("/{provider}/login")]
+async fn provider_login(
+    req: HttpRequest,
+    provider: web::Path<String>,
     query: web::Query<AuthQuery>,
     auth_service: web::Data<Arc<dyn AuthService>>,
-    turnstile_service: web::Data<Arc<TurnstileService>>,
+    captcha_service: web::Data<Arc<CaptchaService>>,
     session: Session,
 ) -> impl Responder {
-    let is_valid = match turnstile_service.verify(&query.turnstile_response).await {
-        Ok(valid) => valid,
-        Err(e) => {
-            log::error!("Failed to verify turnstile token: {}", e);
-            return HttpResponse::InternalServerError().finish();
-        }
+    let Some(provider) = auth_service.provider(&provider) else {
+        return HttpResponse::NotFound().body("Unknown login provider");
     };
 
-    if !is_valid {
-        return HttpResponse::BadRequest().body("Invalid Turnstile token");
+    if let Err(response) = controller::verify_captcha(&req, &captcha_service, &query.captcha_response, "login").await {
+        return response;
     }
 
-    // Set a flag in the session to indicate that Turnstile was passed
-    if session.insert("turnstile_verified", true).is_err() {
+    // Only logins started after passing the captcha have an attempt for the callback to check
+    let attempt = LoginAttempt::new(provider.name());
+    if session.insert("login_attempt", &attempt).is_err() {
         return HttpResponse::InternalServerError().finish();
     }
 
-    let discord_auth_url = auth_service.get_discord_auth_url();
     HttpResponse::Found()
-        .append_header(("Location", discord_auth_url))
+        .append_header(("Location", provider.authorize_url(&attempt)))
         .finish()
 }
 
-#[get("/discord/callback")]
-async fn discord_callback(
+#[get("/{provider}/callback")]
+async fn provider_callback(
+    req: HttpRequest,
+    provider: web::Path<String>,
     query: web::Query<CallbackQuery>,
     auth_service: web::Data<Arc<dyn AuthService>>,
-    pool: web::Data<SqlitePool>,
+    user_service: web::Data<Arc<UserService>>,
+    guild_gate: web::Data<Option<Arc<GuildGate>>>,
     session: Session,
 ) -> impl Responder {
-    // Check if the Turnstile verification flag is in the session
-    if session.get::<bool>("turnstile_verified").unwrap_or(None) != Some(true) {
-        // If not, redirect to login, as the user bypassed the check
-        return HttpResponse::Found()
-            .append_header(("Location", "/login.html"))
-            .finish();
+    let Some(provider) = auth_service.provider(&provider) else {
+        return HttpResponse::NotFound().body("Unknown login provider");
+    };
+
+    // The attempt is removed right away, so a callback can't be replayed
+    let attempt = session.remove_as::<LoginAttempt>("login_attempt").and_then(Result::ok);
+    let Some(attempt) = attempt else {
+        return rejected_login(provider.name(), LoginAttemptError::Missing);
+    };
+    if let Err(e) = attempt.verify(provider.name(), query.state.as_deref().unwrap_or_default()) {
+        return rejected_login(provider.name(), e);
     }
 
-    // Clear the flag immediately to prevent reuse
-    session.remove("turnstile_verified");
+    if let Some(error) = &query.error {
+        return HttpResponse::BadRequest().body(format!("Login failed: {error}"));
+    }
+    let Some(code) = &query.code else {
+        return HttpResponse::BadRequest().body("Login failed: no authorization code provided");
+    };
 
-    let access_token = match auth_service.exchange_code_for_token(&query.code).await {
+    let access_token = match provider
+        .exchange_code_for_token(code, &attempt.code_verifier)
+        .await
+    {
         Ok(token) => token,
         Err(e) => {
             log::error!("Failed to exchange code for token: {}", e);
@@ -113,45 +254,280 @@ async fn discord_callback(
         }
     };
 
-    let discord_user = match auth_service.get_discord_user(&access_token).await {
+    let provider_user = match provider.get_user(&access_token).await {
         Ok(user) => user,
         Err(e) => {
-            log::error!("Failed to get discord user: {}", e);
+            log::error!("Failed to get {} user: {}", provider.name(), e);
             return HttpResponse::InternalServerError().finish();
         }
     };
 
-    let user_id: i64 = discord_user.id.parse().unwrap();
-
-    let today = chrono::Utc::now().date_naive();
-    match sqlx::query!(
-        "INSERT INTO users (id, username, last_request_date) VALUES (?, ?, ?)
-         ON CONFLICT(id) DO UPDATE SET username = excluded.username",
-        user_id,
-        discord_user.username,
-        today
-    )
-    .execute(pool.get_ref())
-    .await
+    // A signed in user links the identity to their account
+    let current_user = session.get::<i64>("user_id").unwrap_or(None);
+
+    // With guild gating, Discord logins are checked right away and other providers only sign in linked accounts
+    let guild_access = match guild_gate.as_ref() {
+        Some(gate) if provider.name() == "discord" => match gate.check_user(&access_token).await {
+            Ok(GuildAccess::Denied(reason)) => {
+                log::info!(
+                    "Rejected discord login of {} ({}): {}",
+                    provider_user.username,
+                    provider_user.subject,
+                    reason
+                );
+                return HttpResponse::Forbidden().body(reason);
+            }
+            Ok(access) => Some(access),
+            Err(e) => {
+                log::error!("Failed to check guild membership: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        Some(_) if current_user.is_none() => {
+            match auth_service.linked_user(provider.name(), &provider_user.subject).await {
+                Ok(Some(_)) => None,
+                Ok(None) => {
+                    return HttpResponse::Forbidden().body(format!(
+                        "Sign in with Discord first, then link your {} account",
+                        provider.display_name()
+                    ));
+                }
+                Err(e) => {
+                    log::error!("Failed to look up identity: {}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+        _ => None,
+    };
+    if current_user.is_none() {
+        match user_service.is_allowed(provider.name(), &provider_user.subject).await {
+            Ok(true) => (),
+            Ok(false) => {
+                log::info!(
+                    "Rejected {} login of {} ({}), not on the allowlist",
+                    provider.name(),
+                    provider_user.username,
+                    provider_user.subject
+                );
+                return HttpResponse::Forbidden().body("This instance is invite only, ask an admin for access");
+            }
+            Err(e) => {
+                log::error!("Failed to check the allowlist: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    let user_id = match auth_service
+        .sign_in(provider.name(), &provider_user, current_user)
+        .await
     {
-        Ok(_) => (),
+        Ok(user_id) => user_id,
+        Err(AuthError::IdentityTaken) => {
+            return HttpResponse::Conflict().body(format!(
+                "This {} account is already linked to another user",
+                provider.display_name()
+            ));
+        }
         Err(e) => {
-            log::error!("Failed to insert or update user: {}", e);
+            log::error!("Failed to sign in user: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    if let (Some(gate), Some(access)) = (guild_gate.as_ref(), &guild_access) {
+        // Mapped guild roles don't replace configured ones
+        let applied = async {
+            gate.apply(user_id, access).await?;
+            user_service.restore_configured_role(user_id).await
+        };
+        if let Err(e) = applied.await {
+            log::error!("Failed to apply guild roles: {}", e);
             return HttpResponse::InternalServerError().finish();
         }
     }
 
-    session.insert("user_id", user_id).unwrap();
+    match user_service.get(user_id).await {
+        Ok(Some(user)) => {
+            if let Some(reason) = user.blocked_reason() {
+                return HttpResponse::Forbidden().body(reason);
+            }
+        }
+        Ok(None) => return HttpResponse::InternalServerError().finish(),
+        Err(e) => {
+            log::error!("Failed to fetch user: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if let Err(e) = start_session(&req, &session, user_id) {
+        log::error!("Failed to start session: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
 
     HttpResponse::Found()
         .append_header(("Location", "/"))
         .finish()
 }
 
+/// Sign the session in, remembering the device for the session list.
+pub fn start_session(req: &HttpRequest, session: &Session, user_id: i64) -> Result<(), SessionInsertError> {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    session::sign_in(session, user_id, user_agent)
+}
+
+fn rejected_login(provider: &str, error: LoginAttemptError) -> HttpResponse {
+    log::warn!("Rejected {} login callback: {}", provider, error);
+    HttpResponse::BadRequest().body(format!("Login failed: {error}"))
+}
+
 #[get("/logout")]
 async fn logout(session: Session) -> impl Responder {
-    session.clear();
+    session.purge();
     HttpResponse::Found()
         .append_header(("Location", "/"))
         .finish()
 }
+
+#[get("/sessions")]
+async fn list_sessions(session: Session, session_store: web::Data<SqliteSessionStore>) -> impl Responder {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    let current = session::session_id(&session);
+    match session_store.list(user_id, current.as_deref()).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(e) => {
+            log::error!("Failed to list sessions: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Sign out on every device, including this one.
+#[delete("/sessions")]
+async fn revoke_sessions(session: Session, session_store: web::Data<SqliteSessionStore>) -> impl Responder {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match session_store.revoke_all(user_id).await {
+        Ok(_) => {
+            session.purge();
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => {
+            log::error!("Failed to revoke sessions: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/sessions/{id}")]
+async fn revoke_session(
+    id: web::Path<String>,
+    session: Session,
+    session_store: web::Data<SqliteSessionStore>,
+) -> impl Responder {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match session_store.revoke(user_id, &id).await {
+        Ok(true) => {
+            if session::session_id(&session).as_deref() == Some(id.as_str()) {
+                session.purge();
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Failed to revoke session: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Everything stored about the user, as a JSON download.
+#[get("/account/export")]
+async fn export_account(
+    req: HttpRequest,
+    session: Session,
+    token_service: web::Data<Arc<TokenService>>,
+    user_service: web::Data<Arc<UserService>>,
+    session_store: web::Data<SqliteSessionStore>,
+) -> impl Responder {
+    let user_id = match token::request_user(&req, &session, &token_service, TokenScope::Account).await {
+        Ok(Some(user_id)) => user_id,
+        _ => return HttpResponse::Unauthorized().finish(),
+    };
+    let current = session::session_id(&session);
+    match (
+        user_service.export(user_id).await,
+        token_service.list(user_id).await,
+        session_store.list(user_id, current.as_deref()).await,
+    ) {
+        (Ok(Some(account)), Ok(api_tokens), Ok(sessions)) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"account.json\""))
+            .json(serde_json::json!({
+                "exported_at": chrono::Utc::now(),
+                "account": account,
+                "api_tokens": api_tokens,
+                "sessions": sessions,
+            })),
+        (Ok(None), _, _) => HttpResponse::Unauthorized().finish(),
+        _ => {
+            log::error!("Failed to export account {}", user_id);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Delete the signed in account, once the username is confirmed.
+#[delete("/account")]
+async fn delete_account(
+    request: web::Json<DeleteAccountRequest>,
+    session: Session,
+    user_service: web::Data<Arc<UserService>>,
+) -> impl Responder {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match user_service.get(user_id).await {
+        Ok(Some(user)) if user.username == request.confirm.trim() => (),
+        Ok(Some(_)) => return HttpResponse::BadRequest().body("Type your username to confirm the deletion"),
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => {
+            log::error!("Failed to fetch user: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    match user_service.delete(user_id).await {
+        Ok(_) => {
+            log::info!("Deleted account {}", user_id);
+            session.purge();
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => {
+            log::error!("Failed to delete account: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_only_to_local_paths() {
+        assert!(is_local_path("/"));
+        assert!(is_local_path("/search?q=rust&engine=ddg"));
+        assert!(!is_local_path("https://evil.example"));
+        assert!(!is_local_path("//evil.example"));
+        assert!(!is_local_path("/\\evil.example"));
+        assert!(!is_local_path("/\t/evil.example"));
+        assert!(!is_local_path(""));
+    }
+}
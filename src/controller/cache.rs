@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use actix_session::Session;
+use actix_web::{get, post, put, web, HttpResponse, Responder, Scope};
+use serde::Deserialize;
+
+use crate::service::{
+    cache::{CacheFilter, QueryCache},
+    user::{Role, UserService},
+};
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    prompt_pattern: Option<String>,
+    engine: Option<String>,
+    older_than_seconds: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateEntryRequest {
+    pinned: Option<bool>,
+    generated_query: Option<String>,
+}
+
+pub fn service() -> Scope {
+    web::scope("/cache")
+        .service(stats)
+        .service(list_entries)
+        .service(update_entry)
+        .service(invalidate)
+}
+
+/// Only admins may administrate the cache.
+async fn is_admin(session: &Session, user_service: &UserService) -> bool {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return false;
+    };
+    match user_service.get(user_id).await {
+        Ok(Some(user)) => user.role == Role::Admin && user.blocked_reason().is_none(),
+        Ok(None) => false,
+        Err(e) => {
+            log::error!("Failed to fetch user: {}", e);
+            false
+        }
+    }
+}
+
+#[get("/stats")]
+async fn stats(
+    session: Session,
+    query_cache: web::Data<Arc<QueryCache>>,
+    user_service: web::Data<Arc<UserService>>,
+) -> impl Responder {
+    if !is_admin(&session, &user_service).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok().json(query_cache.stats())
+}
+
+#[get("/entries")]
+async fn list_entries(
+    query: web::Query<ListQuery>,
+    session: Session,
+    query_cache: web::Data<Arc<QueryCache>>,
+    user_service: web::Data<Arc<UserService>>,
+) -> impl Responder {
+    if !is_admin(&session, &user_service).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let query = query.into_inner();
+    let filter = CacheFilter {
+        prompt_pattern: query.prompt_pattern,
+        engine: query.engine,
+        older_than_seconds: query.older_than_seconds,
+        include_pinned: true,
+    };
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    match query_cache
+        .list(&filter, limit, query.offset.unwrap_or(0))
+        .await
+    {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            log::error!("Failed to list cache entries: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[put("/entries/{id}")]
+async fn update_entry(
+    id: web::Path<i64>,
+    request: web::Json<UpdateEntryRequest>,
+    session: Session,
+    query_cache: web::Data<Arc<QueryCache>>,
+    user_service: web::Data<Arc<UserService>>,
+) -> impl Responder {
+    if !is_admin(&session, &user_service).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let id = id.into_inner();
+
+    let mut found = true;
+    if let Some(pinned) = request.pinned {
+        match query_cache.set_pinned(id, pinned).await {
+            Ok(exists) => found &= exists,
+            Err(e) => {
+                log::error!("Failed to pin cache entry {}: {}", id, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    if let Some(generated_query) = &request.generated_query {
+        match query_cache.edit_query(id, generated_query).await {
+            Ok(exists) => found &= exists,
+            Err(e) => {
+                log::error!("Failed to edit cache entry {}: {}", id, e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    if found {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[post("/invalidate")]
+async fn invalidate(
+    filter: web::Json<CacheFilter>,
+    session: Session,
+    query_cache: web::Data<Arc<QueryCache>>,
+    user_service: web::Data<Arc<UserService>>,
+) -> impl Responder {
+    if !is_admin(&session, &user_service).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    match query_cache.invalidate(&filter).await {
+        Ok(removed) => HttpResponse::Ok().json(serde_json::json!({ "removed": removed })),
+        Err(e) => {
+            log::error!("Failed to invalidate cache entries: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
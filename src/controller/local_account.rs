@@ -0,0 +1,213 @@
+use std::sync::Arc;
+
+use actix_session::Session;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder, Scope};
+use serde::Deserialize;
+
+use crate::{
+    controller::{self, auth},
+    service::{
+        auth::AuthService,
+        captcha::CaptchaService,
+        guild::GuildGate,
+        local_account::{self, LocalAccountError, LocalAccountService, LOCAL_PROVIDER},
+        session::SqliteSessionStore,
+        user::UserService,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+    #[serde(rename = "captcha-response", default)]
+    captcha_response: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterForm {
+    username: String,
+    password: String,
+    invite_code: Option<String>,
+    #[serde(rename = "captcha-response", default)]
+    captcha_response: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+pub fn service() -> Scope {
+    web::scope("/local")
+        .service(register)
+        .service(login)
+        .service(change_password)
+}
+
+fn error_response(error: LocalAccountError) -> HttpResponse {
+    match error {
+        LocalAccountError::Sqlx(_) | LocalAccountError::Hash(_) => {
+            log::error!("Local account error: {}", error);
+            HttpResponse::InternalServerError().finish()
+        }
+        LocalAccountError::InvalidCredentials | LocalAccountError::InvalidInviteCode => {
+            HttpResponse::Forbidden().body(error.to_string())
+        }
+        LocalAccountError::UsernameTaken | LocalAccountError::AlreadyRegistered => {
+            HttpResponse::Conflict().body(error.to_string())
+        }
+        LocalAccountError::InvalidUsername | LocalAccountError::WeakPassword => {
+            HttpResponse::BadRequest().body(error.to_string())
+        }
+    }
+}
+
+/// Sign the session in and go to the search page, unless the user is blocked.
+async fn finish_login(req: &HttpRequest, session: &Session, user_service: &UserService, user_id: i64) -> HttpResponse {
+    match user_service.get(user_id).await {
+        Ok(Some(user)) => {
+            if let Some(reason) = user.blocked_reason() {
+                return HttpResponse::Forbidden().body(reason);
+            }
+        }
+        Ok(None) => return HttpResponse::InternalServerError().finish(),
+        Err(e) => {
+            log::error!("Failed to fetch user: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if let Err(e) = auth::start_session(req, session, user_id) {
+        log::error!("Failed to start session: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Found()
+        .append_header(("Location", "/"))
+        .finish()
+}
+
+/// Register, or add a password to the signed in account.
+#[allow(clippy::too_many_arguments)]
+#[post("/register")]
+async fn register(
+    req: HttpRequest,
+    form: web::Form<RegisterForm>,
+    local_account_service: web::Data<Option<Arc<LocalAccountService>>>,
+    captcha_service: web::Data<Arc<CaptchaService>>,
+    user_service: web::Data<Arc<UserService>>,
+    guild_gate: web::Data<Option<Arc<GuildGate>>>,
+    session: Session,
+) -> impl Responder {
+    let Some(local_account_service) = local_account_service.as_ref() else {
+        return HttpResponse::NotFound().body("Local accounts are disabled");
+    };
+    let captcha = controller::verify_captcha(&req, &captcha_service, &form.captcha_response, "register").await;
+    if let Err(response) = captcha {
+        return response;
+    }
+
+    let current_user = session.get::<i64>("user_id").unwrap_or(None);
+    if current_user.is_none() {
+        if guild_gate.is_some() {
+            return HttpResponse::Forbidden().body("Sign in with Discord first, then add a password");
+        }
+        let username = match local_account::normalize_username(&form.username) {
+            Ok(username) => username,
+            Err(e) => return error_response(e),
+        };
+        match user_service.is_allowed(LOCAL_PROVIDER, &username).await {
+            Ok(true) => (),
+            Ok(false) => {
+                return HttpResponse::Forbidden().body("This instance is invite only, ask an admin for access");
+            }
+            Err(e) => {
+                log::error!("Failed to check the allowlist: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    match local_account_service
+        .register(&form.username, &form.password, form.invite_code.as_deref(), current_user)
+        .await
+    {
+        Ok(user_id) => finish_login(&req, &session, &user_service, user_id).await,
+        Err(e) => error_response(e),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    form: web::Form<LoginForm>,
+    local_account_service: web::Data<Option<Arc<LocalAccountService>>>,
+    captcha_service: web::Data<Arc<CaptchaService>>,
+    auth_service: web::Data<Arc<dyn AuthService>>,
+    user_service: web::Data<Arc<UserService>>,
+    guild_gate: web::Data<Option<Arc<GuildGate>>>,
+    session: Session,
+) -> impl Responder {
+    let Some(local_account_service) = local_account_service.as_ref() else {
+        return HttpResponse::NotFound().body("Local accounts are disabled");
+    };
+    let captcha = controller::verify_captcha(&req, &captcha_service, &form.captcha_response, "login").await;
+    if let Err(response) = captcha {
+        return response;
+    }
+
+    let user_id = match local_account_service.login(&form.username, &form.password).await {
+        Ok(user_id) => user_id,
+        Err(e) => return error_response(e),
+    };
+
+    // With guild gating, only accounts with Discord linked may sign in
+    if guild_gate.is_some() {
+        match auth_service.identities(user_id).await {
+            Ok(identities) if identities.iter().any(|provider| provider == "discord") => (),
+            Ok(_) => return HttpResponse::Forbidden().body("Sign in with Discord first, then add a password"),
+            Err(e) => {
+                log::error!("Failed to fetch identities: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    finish_login(&req, &session, &user_service, user_id).await
+}
+
+/// Change the password and sign out every other session.
+#[post("/password")]
+async fn change_password(
+    req: HttpRequest,
+    request: web::Json<ChangePasswordRequest>,
+    local_account_service: web::Data<Option<Arc<LocalAccountService>>>,
+    session_store: web::Data<SqliteSessionStore>,
+    session: Session,
+) -> impl Responder {
+    let Some(local_account_service) = local_account_service.as_ref() else {
+        return HttpResponse::NotFound().body("Local accounts are disabled");
+    };
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+
+    if let Err(e) = local_account_service
+        .change_password(user_id, &request.current_password, &request.new_password)
+        .await
+    {
+        return error_response(e);
+    }
+
+    if let Err(e) = session_store.revoke_all(user_id).await {
+        log::error!("Failed to revoke sessions: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    if let Err(e) = auth::start_session(&req, &session, user_id) {
+        log::error!("Failed to start session: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::NoContent().finish()
+}
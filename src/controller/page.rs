@@ -0,0 +1,133 @@
+use crate::model::results::{map_citations, Answer, SearchResult};
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Wrap `body` in the page skeleton shared by all server rendered pages.
+pub fn layout(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="UTF-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1.0" />
+    <meta name="referrer" content="no-referrer" />
+    <title>{title} - AI Search</title>
+    <link rel="stylesheet" href="/style.css" />
+  </head>
+  <body class="page">
+    <div class="container page-container">
+{body}
+    </div>
+    <script>
+      if (localStorage.getItem('theme') === 'dark') document.body.classList.add('dark-mode');
+    </script>
+  </body>
+</html>
+"#,
+        title = escape_html(title),
+    )
+}
+
+fn page_header(query: &str) -> String {
+    format!(
+        r#"      <h2><a href="/" class="link">AI Search Bangs</a></h2>
+      <p class="generated-query">Searched for <code>{}</code></p>
+"#,
+        escape_html(query)
+    )
+}
+
+fn results_list(results: &[SearchResult]) -> String {
+    let mut body = String::new();
+    if results.is_empty() {
+        body.push_str("      <p>No results found.</p>\n");
+    }
+
+    body.push_str("      <ol class=\"results\">\n");
+    for result in results {
+        body.push_str(&format!(
+            r#"        <li class="result">
+          <a href="{url}" class="result-title" rel="noopener noreferrer">{title}</a>
+          <div class="result-url">{url}</div>
+          <p class="result-snippet">{snippet}</p>
+        </li>
+"#,
+            url = escape_html(&result.url),
+            title = escape_html(&result.title),
+            snippet = escape_html(&result.snippet),
+        ));
+    }
+    body.push_str("      </ol>\n");
+    body
+}
+
+pub fn results_page(prompt: &str, query: &str, results: &[SearchResult]) -> String {
+    let body = page_header(query) + &results_list(results);
+    layout(prompt, &body)
+}
+
+/// Escape `summary` and turn its `[n]` citations into links to the n-th result.
+fn link_citations(summary: &str, results: &[SearchResult]) -> String {
+    map_citations(&escape_html(summary), |n| {
+        let result = results.get(n.checked_sub(1)?)?;
+        Some(format!(
+            r#"<a href="{}" class="citation" rel="noopener noreferrer">[{}]</a>"#,
+            escape_html(&result.url),
+            n
+        ))
+    })
+}
+
+pub fn answer_page(prompt: &str, query: &str, answer: &Answer) -> String {
+    let mut body = page_header(query);
+    if !answer.summary.is_empty() {
+        body.push_str(&format!(
+            "      <p class=\"answer\">{}</p>\n",
+            link_citations(&answer.summary, &answer.results)
+        ));
+    }
+    body.push_str(&results_list(&answer.results));
+    layout(prompt, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(url: &str) -> SearchResult {
+        SearchResult {
+            title: String::new(),
+            url: url.to_string(),
+            snippet: String::new(),
+        }
+    }
+
+    #[test]
+    fn links_citations_to_results() {
+        let results = [result("https://a.example/"), result("https://b.example/?q=1&x=\"")];
+        assert_eq!(
+            link_citations("See [2] and [1].", &results),
+            "See <a href=\"https://b.example/?q=1&amp;x=&quot;\" class=\"citation\" rel=\"noopener noreferrer\">[2]</a> \
+             and <a href=\"https://a.example/\" class=\"citation\" rel=\"noopener noreferrer\">[1]</a>."
+        );
+    }
+
+    #[test]
+    fn escapes_the_summary_and_keeps_unknown_citations() {
+        let results = [result("https://a.example/")];
+        assert_eq!(link_citations("<b>[0] [3] [x]</b>", &results), "&lt;b&gt;[0] [3] [x]&lt;/b&gt;");
+    }
+}
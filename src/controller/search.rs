@@ -1,104 +1,258 @@
-use std::{env, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
 use actix_session::Session;
 use actix_web::{
     body::BoxBody,
     get,
     web::{self, Redirect},
+    http::header,
     HttpRequest, HttpResponse, Responder, Scope,
 };
-use governor::{clock::DefaultClock, RateLimiter, state::direct::NotKeyed, state::InMemoryState};
-use sqlx::SqlitePool;
 
 use crate::{
-    model::AiSearchQuery,
-    service::search::SearchService,
+    controller::{self, page, token},
+    model::{AiSearchQuery, ResponseFormat, SearchMode},
+    service::{
+        answer::AnswerService,
+        quota::{GuestPolicy, QuotaService, QuotaSubject},
+        rate_limit::RateLimitService,
+        results::ResultsService,
+        risk::{RiskRequest, RiskScorer, CHALLENGE_PASSED_AT},
+        search::{self, SearchError, SearchService},
+        token::{TokenError, TokenScope, TokenService},
+        user::UserService,
+    },
 };
 
 #[get("ai")]
+#[allow(clippy::too_many_arguments)]
 async fn ai_search(
     req: HttpRequest,
     query: web::Query<AiSearchQuery>,
     search_service: web::Data<Arc<dyn SearchService>>,
-    rate_limiter: web::Data<Option<Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>>>,
-    pool: web::Data<SqlitePool>,
+    rate_limit_service: web::Data<Arc<RateLimitService>>,
+    results_service: web::Data<Option<Arc<ResultsService>>>,
+    answer_service: web::Data<Arc<dyn AnswerService>>,
+    quota_service: web::Data<Arc<QuotaService>>,
+    token_service: web::Data<Arc<TokenService>>,
+    user_service: web::Data<Arc<UserService>>,
+    risk_scorer: web::Data<Option<Arc<RiskScorer>>>,
     session: Session,
 ) -> impl Responder {
-    let user_id = match session.get::<i64>("user_id") {
-        Ok(Some(id)) => id,
-        _ => {
-            return HttpResponse::Found()
-                .append_header(("Location", "/login.html"))
-                .finish();
+    let subject = match token::request_user(&req, &session, &token_service, TokenScope::Search).await {
+        Ok(Some(id)) => QuotaSubject::User(id),
+        Ok(None) => {
+            let Some(policy) = quota_service.guest_policy() else {
+                return HttpResponse::Found()
+                    .append_header(("Location", "/login.html"))
+                    .finish();
+            };
+            if policy.require_captcha && session.get::<bool>("guest_verified").unwrap_or(None) != Some(true) {
+                let next = urlencoding::encode(&format!("{}?{}", req.path(), req.query_string())).into_owned();
+                return HttpResponse::Found()
+                    .append_header(("Location", format!("/guest.html?next={next}")))
+                    .finish();
+            }
+            match guest_client(&req, policy) {
+                Some(client) => QuotaSubject::Guest(client),
+                None => return HttpResponse::BadRequest().body("Unable to determine the client address"),
+            }
+        }
+        Err(TokenError::Sqlx(e)) => {
+            log::error!("Failed to authenticate token: {}", e);
+            return HttpResponse::InternalServerError().finish();
         }
+        Err(e) => return HttpResponse::Unauthorized().body(e.to_string()),
     };
 
-    if let Some(limiter) = rate_limiter.as_ref() {
-        if let Err(_) = limiter.check() {
-            return HttpResponse::TooManyRequests().body("Too many requests");
+    if let QuotaSubject::User(user_id) = subject {
+        match user_service.get(user_id).await {
+            Ok(Some(user)) => {
+                if let Some(reason) = user.blocked_reason() {
+                    return HttpResponse::Forbidden().body(reason);
+                }
+            }
+            Ok(None) => return HttpResponse::Unauthorized().finish(),
+            Err(e) => {
+                log::error!("Failed to fetch user: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
         }
     }
 
-    let daily_limit: i32 = env::var("DAILY_REQUEST_LIMIT")
-        .unwrap_or("50".to_string())
-        .parse()
-        .unwrap_or(50);
-
-    let mut user = match sqlx::query!("SELECT * FROM users WHERE id = ?", user_id)
-        .fetch_one(pool.get_ref())
-        .await
+    // Token clients are scripts by design and can't solve a challenge
+    if let Some(risk_scorer) = risk_scorer.as_ref()
+        && token::request_token(&req).is_none()
     {
-        Ok(user) => user,
-        Err(e) => {
-            log::error!("Failed to fetch user: {}", e);
-            return HttpResponse::InternalServerError().finish();
+        let (client, account_created_at) = match &subject {
+            QuotaSubject::User(user_id) => match user_service.created_at(*user_id).await {
+                Ok(created_at) => (format!("user:{user_id}"), created_at),
+                Err(e) => {
+                    log::error!("Failed to fetch account age: {}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            },
+            QuotaSubject::Guest(client) => (format!("guest:{client}"), None),
+        };
+        let assessment = risk_scorer.assess(&RiskRequest {
+            client: &client,
+            prompt: query.q.as_deref().unwrap_or_default(),
+            browser_headers: has_browser_headers(&req),
+            account_created_at,
+        });
+        let passed_at = session.get::<i64>(CHALLENGE_PASSED_AT).unwrap_or(None);
+        if risk_scorer.is_suspicious(&assessment) && !risk_scorer.challenge_valid(passed_at) {
+            log::info!("Challenging {} with score {} ({:?})", client, assessment.score, assessment.signals);
+            let next = urlencoding::encode(&format!("{}?{}", req.path(), req.query_string())).into_owned();
+            return HttpResponse::Found()
+                .append_header(("Location", format!("/challenge.html?next={next}")))
+                .finish();
         }
-    };
+    }
 
-    let today = chrono::Utc::now().date_naive();
-    if user.last_request_date != today {
-        user.request_count = 0;
-        user.last_request_date = today;
+    let user_id = match &subject {
+        QuotaSubject::User(user_id) => Some(*user_id),
+        QuotaSubject::Guest(_) => None,
+    };
+    let ip = controller::client_ip(&req, rate_limit_service.trust_forwarded_for());
+    if let Err(limited) = rate_limit_service.check(user_id, ip) {
+        return too_many_requests(limited.retry_after(), "Too many requests");
     }
 
-    if user.request_count >= daily_limit as i64 {
-        return HttpResponse::TooManyRequests().body("Daily request limit exceeded");
+    let usage = match quota_service.usage(&subject).await {
+        Ok(usage) => usage,
+        Err(e) => {
+            log::error!("Failed to fetch quota usage: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if usage.fresh.is_exhausted() && usage.cached.is_exhausted() {
+        return too_many_requests(QuotaService::until_reset(), "Daily request limit exceeded");
     }
 
     let request = query.into_inner();
+    if request.mode != SearchMode::Redirect && results_service.is_none() {
+        let mode = if request.mode == SearchMode::Answer { "Answer" } else { "Results" };
+        return HttpResponse::BadRequest().body(format!("{mode} mode is not enabled on this instance"));
+    }
     let Some(query) = request.q else {
         return HttpResponse::BadRequest().body("no search content provided");
     };
-    let search_engine = request.engine.unwrap_or("google".to_string());
+    // Answers always need the LLM, so they can't be served from the cache alone
+    if request.mode == SearchMode::Answer && usage.fresh.is_exhausted() {
+        return too_many_requests(QuotaService::until_reset(), "Daily request limit exceeded");
+    }
+    // Without an explicit engine, the `vertical=dev` hint lets the LLM route to a developer engine
+    let search_engine = request.engine.unwrap_or_else(|| {
+        let routed = request.vertical.as_deref().is_some_and(search::is_dev_vertical);
+        if routed { "auto" } else { "google" }.to_string()
+    });
     let language = request.language.unwrap_or("English".to_string());
 
     let result = search_service
-        .generate_query(&query, &search_engine, &language)
+        .generate_query(
+            &query,
+            &search_engine,
+            &language,
+            request.vertical.as_deref(),
+            &request.options,
+            !usage.fresh.is_exhausted(),
+        )
         .await;
 
     let result = match result {
         Ok(result) => result,
+        Err(SearchError::NotCached) => {
+            return too_many_requests(QuotaService::until_reset(), "Daily request limit exceeded");
+        }
         Err(err) => return HttpResponse::InternalServerError().body(format!("{err:?}")),
     };
 
-    match sqlx::query!(
-        "UPDATE users SET request_count = request_count + 1, last_request_date = ? WHERE id = ?",
-        today,
-        user_id
-    )
-    .execute(pool.get_ref())
-    .await
-    {
-        Ok(_) => (),
+    // Cache hits only cost an LLM call once their own counter is used up. Only successful responses are charged.
+    let charge_cached =
+        result.from_cache && request.mode != SearchMode::Answer && !usage.cached.is_exhausted();
+
+    // Entries cached before the raw query was stored can only be redirected to
+    let (SearchMode::Results | SearchMode::Answer, Some(results_service), Some(generated_query)) =
+        (request.mode, results_service.as_ref(), &result.query)
+    else {
+        charge(&quota_service, &subject, charge_cached).await;
+        return redirect(&req, result.url);
+    };
+
+    let results = match results_service.search(generated_query, &result.options).await {
+        Ok(results) => results,
         Err(e) => {
-            log::error!("Failed to update user request count: {}", e);
-            // Decide if you want to fail the whole request here
+            log::error!("Failed to fetch results: {}", e);
+            return HttpResponse::BadGateway().body("Failed to fetch search results");
         }
+    };
+
+    if request.mode == SearchMode::Results {
+        charge(&quota_service, &subject, charge_cached).await;
+        return match request.format {
+            ResponseFormat::Html => html(page::results_page(&query, generated_query, &results)),
+            ResponseFormat::Json => HttpResponse::Ok().json(serde_json::json!({
+                "query": generated_query,
+                "results": results,
+            })),
+        };
     }
 
-    Redirect::to(result.url)
+    let answer = match answer_service.answer(&query, &language, results).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            log::error!("Failed to answer `{}`: {}", query, e);
+            return HttpResponse::InternalServerError().body("Failed to summarize search results");
+        }
+    };
+    charge(&quota_service, &subject, charge_cached).await;
+    match request.format {
+        ResponseFormat::Html => html(page::answer_page(&query, generated_query, &answer)),
+        ResponseFormat::Json => HttpResponse::Ok().json(serde_json::json!({
+            "query": generated_query,
+            "summary": answer.summary,
+            "results": answer.results,
+        })),
+    }
+}
+
+async fn charge(quota_service: &QuotaService, subject: &QuotaSubject, cached: bool) {
+    if let Err(e) = quota_service.charge(subject, cached).await {
+        log::error!("Failed to update request count: {}", e);
+    }
+}
+
+/// Browsers send these with every navigation, plain HTTP clients usually don't.
+fn has_browser_headers(req: &HttpRequest) -> bool {
+    [header::USER_AGENT, header::ACCEPT, header::ACCEPT_LANGUAGE]
+        .iter()
+        .all(|name| req.headers().contains_key(name))
+}
+
+/// Quota key of an unauthenticated client.
+fn guest_client(req: &HttpRequest, policy: &GuestPolicy) -> Option<String> {
+    controller::client_ip(req, policy.trust_forwarded_for).map(|ip| policy.client_key(ip))
+}
+
+fn too_many_requests(retry_after: Duration, body: &'static str) -> HttpResponse {
+    // Whole seconds, rounded up so clients don't retry just before the limit recovers
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, seconds.max(1).to_string()))
+        .body(body)
+}
+
+fn html(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+fn redirect(req: &HttpRequest, url: String) -> HttpResponse {
+    Redirect::to(url)
         .temporary()
-        .respond_to(&req)
+        .respond_to(req)
         .set_body(BoxBody::new("307 Temporary Redirect"))
 }
 
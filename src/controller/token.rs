@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use actix_session::Session;
+use actix_web::{delete, get, http::header, post, web, HttpRequest, HttpResponse, Responder, Scope};
+use serde::Deserialize;
+
+use crate::service::token::{TokenError, TokenScope, TokenService, MAX_EXPIRY_DAYS};
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    name: String,
+    /// Every scope when unset
+    scopes: Option<Vec<TokenScope>>,
+    /// Never expires when unset
+    expires_in_days: Option<i64>,
+}
+
+pub fn service() -> Scope {
+    web::scope("/tokens")
+        .service(list_tokens)
+        .service(create_token)
+        .service(revoke_token)
+}
+
+/// A token passed as `token` parameter or `Authorization: Bearer` header.
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    let query_token = web::Query::<TokenQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().token);
+    let header_token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    query_token.or(header_token)
+}
+
+/// The signed in user, or the owner of the request's token, see `request_token`.
+/// `Ok(None)` when the request carries neither.
+pub async fn request_user(
+    req: &HttpRequest,
+    session: &Session,
+    token_service: &TokenService,
+    scope: TokenScope,
+) -> Result<Option<i64>, TokenError> {
+    match request_token(req) {
+        Some(token) => token_service.authenticate(&token, scope).await.map(Some),
+        None => Ok(session.get::<i64>("user_id").unwrap_or(None)),
+    }
+}
+
+#[get("")]
+async fn list_tokens(session: Session, token_service: web::Data<Arc<TokenService>>) -> impl Responder {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match token_service.list(user_id).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => {
+            log::error!("Failed to list tokens: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("")]
+async fn create_token(
+    request: web::Json<CreateTokenRequest>,
+    session: Session,
+    token_service: web::Data<Arc<TokenService>>,
+) -> impl Responder {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    let name = request.name.trim();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("Token name must not be empty");
+    }
+    if request
+        .expires_in_days
+        .is_some_and(|days| !(1..=MAX_EXPIRY_DAYS).contains(&days))
+    {
+        return HttpResponse::BadRequest().body(format!("Token expiry must be between 1 and {MAX_EXPIRY_DAYS} days"));
+    }
+
+    match token_service
+        .create(user_id, name, request.scopes.as_deref(), request.expires_in_days)
+        .await
+    {
+        // The secret is only ever shown in this response
+        Ok((token, secret)) => HttpResponse::Created().json(serde_json::json!({
+            "token": token,
+            "secret": secret,
+        })),
+        Err(e) => {
+            log::error!("Failed to create token: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/{id}")]
+async fn revoke_token(
+    id: web::Path<i64>,
+    session: Session,
+    token_service: web::Data<Arc<TokenService>>,
+) -> impl Responder {
+    let Ok(Some(user_id)) = session.get::<i64>("user_id") else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match token_service.revoke(user_id, id.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Failed to revoke token: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
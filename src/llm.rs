@@ -31,9 +31,23 @@ impl LLMPrompt {
     }
 }
 
+/// Strip the markdown code fence models sometimes wrap JSON responses in.
+pub fn strip_json_fence(content: &str) -> &str {
+    if content.starts_with("```json") {
+        content.trim_start_matches("```json").trim_end_matches("```")
+    } else {
+        content
+    }
+}
+
 #[async_trait]
 pub trait LargeLanguageModel: Send + Sync {
-    async fn query(&self, model: &str, contents: &Vec<LLMPrompt>) -> Result<String, LLMError>;
+    async fn query(&self, model: &str, contents: &[LLMPrompt]) -> Result<String, LLMError>;
+}
+
+#[async_trait]
+pub trait EmbeddingModel: Send + Sync {
+    async fn embed(&self, model: &str, text: &str) -> Result<Vec<f32>, LLMError>;
 }
 
 #[derive(Clone, Debug)]
@@ -57,11 +71,11 @@ impl Gemini {
 
 #[async_trait]
 impl LargeLanguageModel for Gemini {
-    async fn query(&self, model_id: &str, contents: &Vec<LLMPrompt>) -> Result<String, LLMError> {
+    async fn query(&self, model_id: &str, contents: &[LLMPrompt]) -> Result<String, LLMError> {
         let request_body = GeminiGenerateRequest {
             contents: contents
                 .iter()
-                .map(|prompt| GeminiPrompt::from(prompt))
+                .map(GeminiPrompt::from)
                 .collect(),
             generation_config: GeminiGenerationConfig {
                 response_mime_type: "application/json".to_string(),
@@ -122,6 +136,50 @@ impl LargeLanguageModel for Gemini {
     }
 }
 
+#[async_trait]
+impl EmbeddingModel for Gemini {
+    async fn embed(&self, model_id: &str, text: &str) -> Result<Vec<f32>, LLMError> {
+        let request_body = GeminiEmbedRequest {
+            content: GeminiEmbedContent {
+                parts: vec![GeminiPromptPart::new(text)],
+            },
+        };
+
+        let response = self
+            .client
+            .post(format!("{}/v1beta/models/{}:embedContent", self.api, model_id))
+            .header("x-goog-api-key", &self.api_key)
+            .json(&request_body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<GeminiEmbedResponse>()
+            .await?;
+
+        Ok(response.embedding.values)
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct GeminiEmbedRequest {
+    content: GeminiEmbedContent,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct GeminiEmbedContent {
+    parts: Vec<GeminiPromptPart>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+struct GeminiEmbedResponse {
+    embedding: GeminiEmbedding,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+struct GeminiEmbedding {
+    values: Vec<f32>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 struct GeminiGenerateRequest {
     pub contents: Vec<GeminiPrompt>,
@@ -164,6 +222,7 @@ struct GeminiGenerationConfig {
     temperature: f32,
 }
 
+#[allow(dead_code)]
 mod gemini_response {
     use serde::{Deserialize, Serialize};
 
@@ -1,14 +1,37 @@
-use std::{env, num::NonZeroU32, sync::Arc};
+use std::{collections::HashMap, env, num::NonZeroU32, sync::Arc, time::Duration};
 
 use actix_files as fs;
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-use actix_web::{cookie::Key, web, App, HttpServer};
-use governor::{clock::DefaultClock, Quota, RateLimiter};
+use actix_session::{
+    config::{PersistentSession, TtlExtensionPolicy},
+    SessionMiddleware,
+};
+use actix_web::{
+    cookie::{time, Key},
+    web, App, HttpServer,
+};
+use clap::Parser;
+use cli::{CacheCommand, Cli, Command};
 use llm::Gemini;
-use log::info;
-use service::{auth::AuthServiceImpl, search::SearchServiceImpl, turnstile::TurnstileService};
+use log::{debug, error, info, warn};
+use service::{
+    answer::AnswerServiceImpl,
+    auth::{AuthServiceImpl, Discord, Github, OAuthClient, OAuthProvider, Oidc},
+    cache::{CachePolicy, QueryCache, SemanticCache},
+    captcha::{CaptchaService, CaptchaVerifier, HCaptcha, NoCaptcha, ReCaptcha, Turnstile},
+    guild::{GuildGate, GuildPolicy},
+    local_account::LocalAccountService,
+    quota::{GuestPolicy, QuotaLimits, QuotaService},
+    rate_limit::{RateLimitService, RateLimits},
+    risk::{RiskPolicy, RiskScorer},
+    session::SqliteSessionStore,
+    results::{FixtureResults, ResultsProvider, ResultsService, SearxngResults},
+    search::{canonical_engine, SearchServiceImpl},
+    token::TokenService,
+    user::{Role, UserService},
+};
 use sqlx::SqlitePool;
 
+mod cli;
 mod controller;
 pub mod llm;
 pub mod model;
@@ -16,6 +39,7 @@ pub mod service;
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     dotenvy::dotenv()?;
     env_logger::init();
 
@@ -28,8 +52,97 @@ async fn main() -> anyhow::Result<()> {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let pool = SqlitePool::connect(&database_url).await?;
 
+    // TTLs are in seconds, zero or negative values keep entries forever
+    let parse_ttl = |seconds: &str| -> Option<chrono::Duration> {
+        let seconds: i64 = seconds.trim().parse().expect("Failed to parse cache ttl");
+        (seconds > 0).then(|| chrono::Duration::seconds(seconds))
+    };
+    let cache_ttl = parse_ttl(&env::var("CACHE_TTL_SECONDS").unwrap_or("604800".to_string()));
+    // Comma separated `engine=seconds` pairs
+    let cache_engine_ttl: HashMap<String, Option<chrono::Duration>> = env::var("CACHE_ENGINE_TTL_SECONDS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(engine, seconds)| (canonical_engine(engine), parse_ttl(seconds)))
+        .collect();
+    let cache_max_entries: i64 = env::var("CACHE_MAX_ENTRIES")
+        .unwrap_or("-1".to_string())
+        .parse()
+        .expect("Failed to parse cache max entries");
+    let cache_memory_entries: usize = env::var("CACHE_MEMORY_ENTRIES")
+        .unwrap_or("1000".to_string())
+        .parse()
+        .expect("Failed to parse cache memory entries");
+    // Changes made from the command line reach a running server once its memory copy is this old
+    let cache_memory_ttl = parse_ttl(&env::var("CACHE_MEMORY_TTL_SECONDS").unwrap_or("60".to_string()));
+    let cache_purge_interval: u64 = env::var("CACHE_PURGE_INTERVAL_SECONDS")
+        .unwrap_or("3600".to_string())
+        .parse()
+        .expect("Failed to parse cache purge interval");
+    // Hit counts are collected in memory and written this often
+    let cache_hit_flush_interval: u64 = env::var("CACHE_HIT_FLUSH_INTERVAL_SECONDS")
+        .unwrap_or("60".to_string())
+        .parse()
+        .expect("Failed to parse cache hit flush interval");
+    let cache_policy = CachePolicy {
+        ttl: cache_ttl,
+        engine_ttl: cache_engine_ttl,
+        max_entries: (cache_max_entries >= 0).then_some(cache_max_entries),
+        memory_ttl: cache_memory_ttl,
+    };
+
+    let allowlist_mode: bool = env::var("ALLOWLIST_MODE")
+        .unwrap_or("false".to_string())
+        .parse()
+        .expect("Failed to parse allowlist mode");
+    // Users in both lists get the higher role
+    let mut configured_roles: HashMap<i64, Role> = HashMap::new();
+    for (variable, role) in [("POWER_USER_IDS", Role::Power), ("ADMIN_USER_IDS", Role::Admin)] {
+        let user_ids = env::var(variable).unwrap_or_default();
+        for user_id in user_ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+            let user_id: i64 = user_id.parse().unwrap_or_else(|_| panic!("Failed to parse {variable}"));
+            let configured = configured_roles.entry(user_id).or_default();
+            *configured = (*configured).max(role);
+        }
+    }
+    let configured_admins: Vec<i64> = configured_roles
+        .iter()
+        .filter(|(_, role)| **role == Role::Admin)
+        .map(|(user_id, _)| *user_id)
+        .collect();
+    let user_service = Arc::new(UserService::new(pool.clone(), allowlist_mode, configured_roles));
+
+    // Comma separated `name=url` pairs, the first instance also serves the plain `searxng` engine
+    let searxng_instances: Vec<(String, String)> = env::var("SEARXNG_INSTANCES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(name, url)| (name.trim().to_lowercase(), url.trim().to_string()))
+        .collect();
+
+    // Only warming the cache needs the LLM, other commands only need the database
+    let warm_args = match cli.command {
+        Some(Command::Cache(CacheCommand::Warm(args))) => Some(args),
+        Some(Command::Cache(CacheCommand::Admin(command))) => {
+            let query_cache = QueryCache::new(pool.clone(), cache_policy, cache_memory_entries, None);
+            let result = cli::run_cache_command(&query_cache, &searxng_instances, command).await;
+            query_cache.flush_hits().await?;
+            return result;
+        }
+        Some(Command::User(command)) => return cli::run_user_command(&user_service, command).await,
+        None => None,
+    };
+
     let session_secret_key = env::var("SESSION_SECRET_KEY").expect("SESSION_SECRET_KEY must be set");
     let session_key = Key::from(session_secret_key.as_bytes());
+    let session_max_age: i64 = env::var("SESSION_MAX_AGE_SECONDS")
+        .unwrap_or("2592000".to_string())
+        .parse()
+        .expect("Failed to parse session max age");
+    let session_idle_timeout: i64 = env::var("SESSION_IDLE_TIMEOUT_SECONDS")
+        .unwrap_or("604800".to_string())
+        .parse()
+        .expect("Failed to parse session idle timeout");
 
     let gemini_key = env::var("GEMINI_KEY").expect("Gemini key is not set");
     let gemini_api =
@@ -39,62 +152,472 @@ async fn main() -> anyhow::Result<()> {
         .parse()
         .expect("Failed to parse temperature");
     let prompt_file = env::var("PROMPT_FILE").unwrap_or("prompt.md".to_string());
-    let requests_per_minute: i32 = env::var("REQUESTS_PER_MINUTE")
+    // Daily limits for fresh generations and cache hits, negative values disable them
+    let daily_request_limit: i64 = env::var("DAILY_REQUEST_LIMIT")
+        .unwrap_or("50".to_string())
+        .parse()
+        .expect("Failed to parse daily request limit");
+    let daily_cached_request_limit: i64 = env::var("DAILY_CACHED_REQUEST_LIMIT")
         .unwrap_or("-1".to_string())
         .parse()
-        .expect("Failed to parse requests per minute");
-
-    let rate_limiter: Option<Arc<RateLimiter<governor::state::direct::NotKeyed, governor::state::InMemoryState, DefaultClock>>> = if requests_per_minute > 0 {
-        let quota = Quota::per_minute(NonZeroU32::new(requests_per_minute as u32).unwrap());
-        Some(Arc::new(RateLimiter::direct(quota)))
-    } else {
-        None
+        .expect("Failed to parse daily cached request limit");
+    // Guest mode is off unless guests get a daily limit
+    let guest_daily_request_limit: i64 = env::var("GUEST_DAILY_REQUEST_LIMIT")
+        .unwrap_or("0".to_string())
+        .parse()
+        .expect("Failed to parse guest daily request limit");
+    let guest_daily_cached_request_limit: i64 = env::var("GUEST_DAILY_CACHED_REQUEST_LIMIT")
+        .unwrap_or("-1".to_string())
+        .parse()
+        .expect("Failed to parse guest daily cached request limit");
+    let trust_forwarded_for: bool = env::var("TRUST_FORWARDED_FOR")
+        .unwrap_or("false".to_string())
+        .parse()
+        .expect("Failed to parse trust forwarded for");
+    // Requests per minute, zero or negative values disable a limit
+    let per_minute = |variable: &str| -> Option<NonZeroU32> {
+        let requests: i64 = env::var(variable)
+            .unwrap_or("-1".to_string())
+            .parse()
+            .unwrap_or_else(|_| panic!("Failed to parse {variable}"));
+        u32::try_from(requests).ok().and_then(NonZeroU32::new)
     };
+    let rate_limit_service = Arc::new(RateLimitService::new(
+        RateLimits {
+            per_user: per_minute("USER_REQUESTS_PER_MINUTE"),
+            per_ip: per_minute("IP_REQUESTS_PER_MINUTE"),
+            global: per_minute("REQUESTS_PER_MINUTE"),
+        },
+        trust_forwarded_for,
+    ));
+    // Clients whose limits have recovered are forgotten this often
+    let rate_limit_purge_interval: u64 = env::var("RATE_LIMIT_PURGE_INTERVAL_SECONDS")
+        .unwrap_or("300".to_string())
+        .parse()
+        .expect("Failed to parse rate limit purge interval");
+    let guest_policy = (guest_daily_request_limit != 0).then(|| GuestPolicy {
+        limits: QuotaLimits {
+            fresh: (guest_daily_request_limit >= 0).then_some(guest_daily_request_limit),
+            cached: (guest_daily_cached_request_limit >= 0).then_some(guest_daily_cached_request_limit),
+        },
+        ipv4_prefix: env::var("GUEST_IPV4_PREFIX")
+            .unwrap_or("32".to_string())
+            .parse()
+            .expect("Failed to parse guest ipv4 prefix"),
+        ipv6_prefix: env::var("GUEST_IPV6_PREFIX")
+            .unwrap_or("64".to_string())
+            .parse()
+            .expect("Failed to parse guest ipv6 prefix"),
+        // GUEST_REQUIRE_TURNSTILE is the name from before other captchas were supported
+        require_captcha: env::var("GUEST_REQUIRE_CAPTCHA")
+            .or_else(|_| env::var("GUEST_REQUIRE_TURNSTILE"))
+            .unwrap_or("false".to_string())
+            .parse()
+            .expect("Failed to parse guest require captcha"),
+        trust_forwarded_for,
+    });
+    let power_daily_request_limit: i64 = env::var("POWER_DAILY_REQUEST_LIMIT")
+        .unwrap_or("500".to_string())
+        .parse()
+        .expect("Failed to parse power daily request limit");
+    let power_daily_cached_request_limit: i64 = env::var("POWER_DAILY_CACHED_REQUEST_LIMIT")
+        .unwrap_or("-1".to_string())
+        .parse()
+        .expect("Failed to parse power daily cached request limit");
+    let quota_service = Arc::new(QuotaService::new(
+        pool.clone(),
+        QuotaLimits {
+            fresh: (daily_request_limit >= 0).then_some(daily_request_limit),
+            cached: (daily_cached_request_limit >= 0).then_some(daily_cached_request_limit),
+        },
+        QuotaLimits {
+            fresh: (power_daily_request_limit >= 0).then_some(power_daily_request_limit),
+            cached: (power_daily_cached_request_limit >= 0).then_some(power_daily_cached_request_limit),
+        },
+        guest_policy,
+    ));
+
+    // Roles and allowlist entries from the config are applied on every start, configured roles can't be changed
+    // any other way
+    for user_id in user_service.apply_configured_roles().await? {
+        warn!("User {} from POWER_USER_IDS or ADMIN_USER_IDS doesn't exist", user_id);
+    }
+    // Comma separated `provider:subject` identities, e.g. `discord:80351110224678912`
+    let login_allowlist = env::var("LOGIN_ALLOWLIST").unwrap_or_default();
+    for identity in login_allowlist.split(',').map(str::trim).filter(|identity| !identity.is_empty()) {
+        let (provider, subject) = identity
+            .split_once(':')
+            .expect("LOGIN_ALLOWLIST entries must look like `provider:subject`");
+        user_service.allow(provider, subject).await?;
+    }
+
+    let token_service = Arc::new(TokenService::new(pool.clone()));
+    let session_store = SqliteSessionStore::new(
+        pool.clone(),
+        chrono::Duration::seconds(session_max_age),
+        (session_idle_timeout > 0).then(|| chrono::Duration::seconds(session_idle_timeout)),
+    );
 
     let llm_model = env::var("LLM_MODEL").unwrap_or("gemini-1.5-flash".to_string());
+    // Results mode is only available when a provider is configured
+    let results_provider: Option<Box<dyn ResultsProvider>> =
+        match env::var("RESULTS_PROVIDER").unwrap_or_default().as_str() {
+            "" => None,
+            "searxng" => {
+                let (_, base_url) = searxng_instances
+                    .first()
+                    .expect("RESULTS_PROVIDER=searxng needs SEARXNG_INSTANCES to be set");
+                Some(Box::new(SearxngResults::new(base_url)))
+            }
+            "fixture" => {
+                let fixture_file =
+                    env::var("RESULTS_FIXTURE_FILE").expect("RESULTS_FIXTURE_FILE must be set");
+                Some(Box::new(
+                    FixtureResults::new(&fixture_file).expect("Failed to load results fixture"),
+                ))
+            }
+            provider => panic!("Unknown results provider {provider}"),
+        };
+    let mut results_blocklist: Vec<String> = env::var("RESULTS_BLOCKLIST")
+        .unwrap_or_default()
+        .split(',')
+        .map(|domain| domain.to_string())
+        .collect();
+    if let Some(blocklist_file) = env::var("RESULTS_BLOCKLIST_FILE").ok().filter(|file| !file.is_empty()) {
+        let content =
+            std::fs::read_to_string(blocklist_file).expect("Failed to read results blocklist file");
+        results_blocklist.extend(
+            content
+                .lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .map(|line| line.to_string()),
+        );
+    }
+    let results_service: Option<Arc<ResultsService>> = results_provider
+        .map(|provider| Arc::new(ResultsService::new(provider, results_blocklist)));
+
+    let answer_prompt_file = env::var("ANSWER_PROMPT_FILE").unwrap_or("answer_prompt.md".to_string());
+    let answer_top_n: usize = env::var("ANSWER_TOP_N")
+        .unwrap_or("8".to_string())
+        .parse()
+        .expect("Failed to parse answer top n");
+
+    // Semantic matching is off unless a similarity threshold is set
+    let semantic_cache = env::var("SEMANTIC_CACHE_THRESHOLD")
+        .ok()
+        .filter(|threshold| !threshold.is_empty())
+        .map(|threshold| SemanticCache {
+            model: Box::new(Gemini::new(gemini_api.clone(), gemini_key.clone(), temperature)),
+            model_id: env::var("EMBEDDING_MODEL").unwrap_or("text-embedding-004".to_string()),
+            threshold: threshold.parse().expect("Failed to parse semantic cache threshold"),
+            max_candidates: env::var("SEMANTIC_CACHE_MAX_CANDIDATES")
+                .unwrap_or("1000".to_string())
+                .parse()
+                .expect("Failed to parse semantic cache max candidates"),
+        });
+
+    let query_cache = Arc::new(QueryCache::new(pool.clone(), cache_policy, cache_memory_entries, semantic_cache));
+    // Entries cached before expiry was stored or under other TTLs follow the current ones
+    let reexpired = query_cache.apply_policy().await?;
+    if reexpired > 0 {
+        info!("Updated the expiry of {} cached queries", reexpired);
+    }
 
     let llm = Gemini::new(gemini_api, gemini_key, temperature);
+    let answer_service: Arc<dyn service::answer::AnswerService> = Arc::new(AnswerServiceImpl::new(
+        Box::new(llm.clone()),
+        llm_model.clone(),
+        answer_prompt_file,
+        answer_top_n,
+    ));
     let search_service: Arc<dyn service::search::SearchService> = Arc::new(SearchServiceImpl::new(
         Box::new(llm),
         llm_model,
         prompt_file,
-        pool.clone(),
+        query_cache.clone(),
+        searxng_instances,
     ));
 
-    let discord_client_id = env::var("DISCORD_CLIENT_ID").expect("DISCORD_CLIENT_ID must be set");
-    let discord_client_secret =
-        env::var("DISCORD_CLIENT_SECRET").expect("DISCORD_CLIENT_SECRET must be set");
-    let discord_redirect_uri =
-        env::var("DISCORD_REDIRECT_URI").expect("DISCORD_REDIRECT_URI must be set");
-    let auth_service: Arc<dyn service::auth::AuthService> = Arc::new(AuthServiceImpl::new(
-        discord_client_id,
-        discord_client_secret,
-        discord_redirect_uri,
-    ));
+    if let Some(warm_args) = warm_args {
+        return cli::warm_cache(search_service.as_ref(), warm_args).await;
+    }
+
+    // Discord guild gating is on when guild ids are configured
+    let comma_separated = |variable: &str| -> Vec<String> {
+        env::var(variable)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let guild_ids = comma_separated("DISCORD_GUILD_IDS");
+    let guild_gate = (!guild_ids.is_empty()).then(|| {
+        let policy = GuildPolicy {
+            guild_ids,
+            required_role_ids: comma_separated("DISCORD_REQUIRED_ROLE_IDS"),
+            // Comma separated `role_id=role` pairs, e.g. `1234=power,5678=admin`
+            role_mapping: comma_separated("DISCORD_ROLE_MAPPING")
+                .iter()
+                .map(|entry| {
+                    let (role_id, role) = entry
+                        .split_once('=')
+                        .expect("DISCORD_ROLE_MAPPING entries must look like `role_id=role`");
+                    (role_id.to_string(), role.parse().expect("Failed to parse DISCORD_ROLE_MAPPING role"))
+                })
+                .collect(),
+        };
+        let bot_token = env::var("DISCORD_BOT_TOKEN").ok().filter(|token| !token.is_empty());
+        Arc::new(GuildGate::new(pool.clone(), policy, bot_token, configured_admins))
+    });
+    let guild_recheck_interval: u64 = env::var("DISCORD_GUILD_RECHECK_SECONDS")
+        .unwrap_or("86400".to_string())
+        .parse()
+        .expect("Failed to parse guild re-check interval");
+
+    // A login provider is enabled when its client id is set
+    let oauth_client = |prefix: &str| -> Option<OAuthClient> {
+        let client_id = env::var(format!("{prefix}_CLIENT_ID")).ok().filter(|id| !id.is_empty())?;
+        Some(OAuthClient {
+            client_id,
+            client_secret: env::var(format!("{prefix}_CLIENT_SECRET"))
+                .unwrap_or_else(|_| panic!("{prefix}_CLIENT_SECRET must be set")),
+            redirect_uri: env::var(format!("{prefix}_REDIRECT_URI"))
+                .unwrap_or_else(|_| panic!("{prefix}_REDIRECT_URI must be set")),
+        })
+    };
+    let mut oauth_providers: Vec<Box<dyn OAuthProvider>> = Vec::new();
+    if let Some(client) = oauth_client("DISCORD") {
+        oauth_providers.push(Box::new(Discord::new(client, guild_gate.is_some())));
+    }
+    if let Some(client) = oauth_client("GITHUB") {
+        oauth_providers.push(Box::new(Github::new(client)));
+    }
+    if let Some(client) = oauth_client("GOOGLE") {
+        oauth_providers.push(Box::new(Oidc::google(client)));
+    }
+    if let Some(client) = oauth_client("OIDC") {
+        let issuer = env::var("OIDC_ISSUER").expect("OIDC_ISSUER must be set");
+        let name = env::var("OIDC_NAME").unwrap_or("oidc".to_string());
+        let display_name = env::var("OIDC_DISPLAY_NAME").unwrap_or("Single Sign-On".to_string());
+        oauth_providers.push(Box::new(
+            Oidc::discover(&name, &display_name, &issuer, client)
+                .await
+                .expect("Failed to discover OIDC provider"),
+        ));
+    }
+    let local_accounts: bool = env::var("LOCAL_ACCOUNTS")
+        .unwrap_or("false".to_string())
+        .parse()
+        .expect("Failed to parse local accounts");
+    let local_account_service = local_accounts.then(|| {
+        let invite_code = env::var("LOCAL_INVITE_CODE").ok().filter(|code| !code.is_empty());
+        Arc::new(LocalAccountService::new(pool.clone(), invite_code))
+    });
+    if oauth_providers.is_empty() && local_account_service.is_none() {
+        warn!("No login provider is configured, nobody can sign in");
+    }
+    if guild_gate.is_some() && !oauth_providers.iter().any(|provider| provider.name() == "discord") {
+        warn!("DISCORD_GUILD_IDS is set but Discord login isn't configured, nobody can sign in");
+    }
+    let auth_service: Arc<dyn service::auth::AuthService> =
+        Arc::new(AuthServiceImpl::new(pool.clone(), oauth_providers));
+
+    let captcha_provider = env::var("CAPTCHA_PROVIDER").unwrap_or("turnstile".to_string());
+    // Turnstile keys may also be set with their names from before other captchas were supported
+    let legacy_keys = captcha_provider == "turnstile" && env::var("CAPTCHA_SECRET_KEY").is_err();
+    let captcha_keys = || -> (String, String) {
+        let (site_key, secret_key) = if legacy_keys {
+            ("CLOUDFLARE_TURNSTILE_SITE_KEY", "CLOUDFLARE_TURNSTILE_SECRET_KEY")
+        } else {
+            ("CAPTCHA_SITE_KEY", "CAPTCHA_SECRET_KEY")
+        };
+        (
+            env::var(site_key).unwrap_or_else(|_| panic!("{site_key} must be set")),
+            env::var(secret_key).unwrap_or_else(|_| panic!("{secret_key} must be set")),
+        )
+    };
+    let captcha_verifier: Box<dyn CaptchaVerifier> = match captcha_provider.as_str() {
+        "turnstile" => {
+            let (site_key, secret_key) = captcha_keys();
+            Box::new(Turnstile::new(site_key, secret_key))
+        }
+        "hcaptcha" => {
+            let (site_key, secret_key) = captcha_keys();
+            Box::new(HCaptcha::new(site_key, secret_key))
+        }
+        "recaptcha" => {
+            let (site_key, secret_key) = captcha_keys();
+            let min_score: f64 = env::var("RECAPTCHA_MIN_SCORE")
+                .unwrap_or("0.5".to_string())
+                .parse()
+                .expect("Failed to parse reCAPTCHA min score");
+            Box::new(ReCaptcha::new(site_key, secret_key, min_score))
+        }
+        "none" => {
+            warn!("CAPTCHA_PROVIDER is none, logins and guests aren't checked for bots");
+            Box::new(NoCaptcha)
+        }
+        other => panic!("Unknown CAPTCHA_PROVIDER {other}, expected turnstile, hcaptcha, recaptcha or none"),
+    };
+    let captcha_hostname = env::var("CAPTCHA_HOSTNAME").ok().filter(|hostname| !hostname.is_empty());
+    let captcha_service = Arc::new(CaptchaService::new(captcha_verifier, captcha_hostname, trust_forwarded_for));
+
+    // Searches that look automated must solve a captcha first, a threshold of zero disables this
+    let risk_threshold: u32 = env::var("RISK_CHALLENGE_THRESHOLD")
+        .unwrap_or("50".to_string())
+        .parse()
+        .expect("Failed to parse risk challenge threshold");
+    let risk_seconds = |variable: &str, default: &str| -> u64 {
+        env::var(variable)
+            .unwrap_or(default.to_string())
+            .parse()
+            .unwrap_or_else(|_| panic!("Failed to parse {variable}"))
+    };
+    let risk_scorer = (risk_threshold > 0).then(|| {
+        Arc::new(RiskScorer::new(RiskPolicy {
+            threshold: risk_threshold,
+            burst_window: Duration::from_secs(risk_seconds("RISK_BURST_WINDOW_SECONDS", "60")),
+            burst_limit: env::var("RISK_BURST_LIMIT")
+                .unwrap_or("10".to_string())
+                .parse()
+                .expect("Failed to parse risk burst limit"),
+            repeat_window: Duration::from_secs(risk_seconds("RISK_REPEAT_WINDOW_SECONDS", "600")),
+            repeat_limit: env::var("RISK_REPEAT_LIMIT")
+                .unwrap_or("3".to_string())
+                .parse()
+                .expect("Failed to parse risk repeat limit"),
+            new_account_age: chrono::Duration::seconds(risk_seconds("RISK_NEW_ACCOUNT_SECONDS", "86400") as i64),
+            challenge_ttl: chrono::Duration::seconds(risk_seconds("RISK_CHALLENGE_TTL_SECONDS", "3600") as i64),
+        }))
+    });
+
+    if cache_purge_interval > 0 {
+        let query_cache = query_cache.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(cache_purge_interval));
+            loop {
+                interval.tick().await;
+                if let Err(e) = query_cache.purge().await {
+                    error!("Failed to purge cache: {}", e);
+                }
+            }
+        });
+    }
+
+    {
+        let session_store = session_store.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = session_store.purge().await {
+                    error!("Failed to purge sessions: {}", e);
+                }
+            }
+        });
+    }
+
+    if rate_limit_purge_interval > 0 {
+        let rate_limit_service = rate_limit_service.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(rate_limit_purge_interval));
+            loop {
+                interval.tick().await;
+                let remaining = rate_limit_service.purge();
+                debug!("Rate limiters track {} clients", remaining);
+            }
+        });
+    }
 
-    let turnstile_secret_key =
-        env::var("CLOUDFLARE_TURNSTILE_SECRET_KEY").expect("CLOUDFLARE_TURNSTILE_SECRET_KEY must be set");
-    let turnstile_service = Arc::new(TurnstileService::new(turnstile_secret_key));
+    if quota_service.guest_policy().is_some() {
+        let quota_service = quota_service.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(24 * 60 * 60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = quota_service.purge_guests().await {
+                    error!("Failed to purge guest quotas: {}", e);
+                }
+            }
+        });
+    }
+
+    if let Some(guild_gate) = guild_gate.clone()
+        && guild_gate.can_recheck()
+        && guild_recheck_interval > 0
+    {
+        let user_service = user_service.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(guild_recheck_interval));
+            loop {
+                interval.tick().await;
+                if let Err(e) = guild_gate.recheck().await {
+                    error!("Failed to re-check guild membership: {}", e);
+                }
+                // Mapped guild roles don't replace configured ones
+                if let Err(e) = user_service.apply_configured_roles().await {
+                    error!("Failed to restore configured roles: {}", e);
+                }
+            }
+        });
+    }
+
+    if cache_hit_flush_interval > 0 {
+        let query_cache = query_cache.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(cache_hit_flush_interval));
+            loop {
+                interval.tick().await;
+                if let Err(e) = query_cache.flush_hits().await {
+                    error!("Failed to write cache hits: {}", e);
+                }
+            }
+        });
+    }
 
     info!("Start AI search bangs service at {host}:{port}");
+    let shutdown_cache = query_cache.clone();
 
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(search_service.clone()))
             .app_data(web::Data::new(auth_service.clone()))
-            .app_data(web::Data::new(turnstile_service.clone()))
-            .app_data(web::Data::new(rate_limiter.clone()))
-            .wrap(SessionMiddleware::new(
-                CookieSessionStore::default(),
-                session_key.clone(),
-            ))
+            .app_data(web::Data::new(captcha_service.clone()))
+            .app_data(web::Data::new(rate_limit_service.clone()))
+            .app_data(web::Data::new(results_service.clone()))
+            .app_data(web::Data::new(answer_service.clone()))
+            .app_data(web::Data::new(query_cache.clone()))
+            .app_data(web::Data::new(quota_service.clone()))
+            .app_data(web::Data::new(token_service.clone()))
+            .app_data(web::Data::new(user_service.clone()))
+            .app_data(web::Data::new(guild_gate.clone()))
+            .app_data(web::Data::new(session_store.clone()))
+            .app_data(web::Data::new(local_account_service.clone()))
+            .app_data(web::Data::new(risk_scorer.clone()))
+            .wrap(
+                SessionMiddleware::builder(session_store.clone(), session_key.clone())
+                    .session_lifecycle(
+                        PersistentSession::default()
+                            .session_ttl(time::Duration::seconds(session_store.max_age().num_seconds()))
+                            // Lets the store track activity for the idle timeout
+                            .session_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest),
+                    )
+                    .build(),
+            )
             .service(controller::auth::service())
             .service(controller::search::service())
+            .service(controller::cache::service())
+            .service(controller::token::service())
             .service(fs::Files::new("/", "./static").index_file("index.html"))
     })
     .bind((host, port))?
     .run()
     .await?;
+
+    // Hits since the last flush would be lost otherwise
+    shutdown_cache.flush_hits().await?;
     Ok(())
 }
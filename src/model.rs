@@ -1,15 +1,48 @@
+use search::SearchOptions;
+
+pub mod results;
 pub mod search;
 
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// Redirect to the search engine
+    #[default]
+    Redirect,
+    /// Fetch results ourselves and render them
+    Results,
+    /// Fetch results, re-rank them with the LLM and summarize them
+    Answer,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    #[default]
+    Html,
+    Json,
+}
+
 #[derive(serde::Deserialize, Debug, Clone, PartialEq)]
 pub struct AiSearchQuery {
     pub q: Option<String>,
     pub engine: Option<String>,
     pub language: Option<String>,
+    pub vertical: Option<String>,
+    #[serde(default)]
+    pub mode: SearchMode,
+    #[serde(default)]
+    pub format: ResponseFormat,
+    #[serde(flatten)]
+    pub options: SearchOptions,
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct GenerateQueryResult {
     pub url: String, // url to search service
+    pub query: Option<String>, // raw generated query, missing for entries cached before it was stored
+    pub options: SearchOptions,
+    pub from_cache: bool, // served without calling the LLM
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
@@ -17,4 +50,9 @@ pub struct UserQueryRequest {
     pub engine: String,
     pub prompt: String,
     pub language: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vertical: Option<String>,
+    /// Engines the LLM may route to for `engine=auto`, any vertical engine when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engines: Option<Vec<String>>,
 }
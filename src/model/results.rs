@@ -0,0 +1,43 @@
+/// A single search result, independent of the provider it came from.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    pub title: String,
+    pub url: String,
+    #[serde(default)]
+    pub snippet: String,
+}
+
+/// LLM summary over re-ranked results, `[n]` in the summary cites the n-th result.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct Answer {
+    pub summary: String,
+    pub results: Vec<SearchResult>,
+}
+
+/// Replace each `[n]` citation in `text` with what `replace` returns for `n`, `None` keeps the citation as it is.
+pub fn map_citations(text: &str, mut replace: impl FnMut(usize) -> Option<String>) -> String {
+    let mut mapped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        mapped.push_str(&rest[..start]);
+        let candidate = &rest[start + 1..];
+        let citation = candidate
+            .find(']')
+            .and_then(|end| candidate[..end].parse::<usize>().ok().map(|n| (n, end)));
+        match citation {
+            Some((n, end)) => {
+                match replace(n) {
+                    Some(replacement) => mapped.push_str(&replacement),
+                    None => mapped.push_str(&rest[start..start + end + 2]),
+                }
+                rest = &candidate[end + 1..];
+            }
+            None => {
+                mapped.push('[');
+                rest = candidate;
+            }
+        }
+    }
+    mapped.push_str(rest);
+    mapped
+}
@@ -1,5 +1,150 @@
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SafeSearch {
+    Off,
+    Moderate,
+    Strict,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Freshness {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Freshness {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Freshness::Day => "day",
+            Freshness::Week => "week",
+            Freshness::Month => "month",
+            Freshness::Year => "year",
+        }
+    }
+}
+
+/// Engine options, filled from request params and from what the LLM detected in the prompt.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SearchOptions {
+    /// Language code such as `en` or `zh-CN`
+    pub locale: Option<String>,
+    /// Country code such as `us` or `de`
+    pub region: Option<String>,
+    pub safe_search: Option<SafeSearch>,
+    pub freshness: Option<Freshness>,
+    /// SearXNG categories
+    pub categories: Option<String>,
+}
+
+impl SearchOptions {
+    /// Fill every unset option from `fallback`.
+    pub fn or(self, fallback: SearchOptions) -> SearchOptions {
+        SearchOptions {
+            locale: self.locale.or(fallback.locale),
+            region: self.region.or(fallback.region),
+            safe_search: self.safe_search.or(fallback.safe_search),
+            freshness: self.freshness.or(fallback.freshness),
+            categories: self.categories.or(fallback.categories),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &SearchOptions::default()
+    }
+
+    /// Primary language subtag, e.g. `zh` for `zh-CN`.
+    fn language(&self) -> Option<String> {
+        self.locale
+            .as_deref()
+            .and_then(|locale| locale.split(['-', '_']).next())
+            .filter(|language| !language.is_empty())
+            .map(|language| language.to_lowercase())
+    }
+}
+
+/// Locale of a language as the `language` param names it, e.g. `en` for `English`. Locale codes pass through.
+pub fn language_locale(language: &str) -> Option<String> {
+    let language = language.trim();
+    let locale = match language.to_lowercase().as_str() {
+        "english" => "en",
+        "chinese" | "simplified chinese" => "zh-CN",
+        "traditional chinese" => "zh-TW",
+        "japanese" => "ja",
+        "korean" => "ko",
+        "french" => "fr",
+        "german" => "de",
+        "spanish" => "es",
+        "portuguese" => "pt",
+        "italian" => "it",
+        "russian" => "ru",
+        "ukrainian" => "uk",
+        "polish" => "pl",
+        "dutch" => "nl",
+        "swedish" => "sv",
+        "norwegian" => "no",
+        "danish" => "da",
+        "finnish" => "fi",
+        "czech" => "cs",
+        "greek" => "el",
+        "turkish" => "tr",
+        "arabic" => "ar",
+        "hebrew" => "he",
+        "hindi" => "hi",
+        "thai" => "th",
+        "vietnamese" => "vi",
+        "indonesian" => "id",
+        _ => {
+            // Already a code such as `en` or `pt-BR`
+            let mut parts = language.split(['-', '_']);
+            let primary = parts.next().unwrap_or_default();
+            let is_code = (2..=3).contains(&primary.len())
+                && primary.chars().all(|c| c.is_ascii_alphabetic())
+                && parts.all(|part| (2..=4).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric()));
+            return is_code.then(|| language.to_string());
+        }
+    };
+    Some(locale.to_string())
+}
+
+/// Append the present parameters to `url` as url-encoded `&key=value` pairs.
+fn with_params(mut url: String, params: &[(&str, Option<String>)]) -> String {
+    for (key, value) in params {
+        if let Some(value) = value.as_deref().filter(|value| !value.is_empty()) {
+            url.push_str(&format!("&{key}={}", urlencoding::encode(value)));
+        }
+    }
+    url
+}
+
+/// DuckDuckGo region setting, e.g. `us-en`.
+fn duckduckgo_params(options: &SearchOptions) -> Vec<(&'static str, Option<String>)> {
+    let region = options.region.as_deref().map(|region| {
+        let language = options.language().unwrap_or(region.to_lowercase());
+        format!("{}-{language}", region.to_lowercase())
+    });
+    let safe_search = options.safe_search.map(|safe_search| match safe_search {
+        SafeSearch::Off => "-2",
+        SafeSearch::Moderate => "-1",
+        SafeSearch::Strict => "1",
+    });
+    let freshness = options.freshness.map(|freshness| match freshness {
+        Freshness::Day => "d",
+        Freshness::Week => "w",
+        Freshness::Month => "m",
+        Freshness::Year => "y",
+    });
+    vec![
+        ("kl", region),
+        ("kp", safe_search.map(str::to_string)),
+        ("df", freshness.map(str::to_string)),
+    ]
+}
+
 pub trait SearchEngine: Send + Sync {
-    fn generate_url(&self, query: &str) -> String;
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String;
     fn name(&self) -> String;
 }
 
@@ -7,8 +152,11 @@ pub trait SearchEngine: Send + Sync {
 pub struct Duckduckgo {}
 
 impl SearchEngine for Duckduckgo {
-    fn generate_url(&self, query: &str) -> String {
-        format!("https://duckduckgo.com/?q={query}")
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        with_params(
+            format!("https://duckduckgo.com/?q={query}"),
+            &duckduckgo_params(options),
+        )
     }
 
     fn name(&self) -> String {
@@ -20,8 +168,11 @@ impl SearchEngine for Duckduckgo {
 pub struct DuckduckgoLite {}
 
 impl SearchEngine for DuckduckgoLite {
-    fn generate_url(&self, query: &str) -> String {
-        format!("https://lite.duckduckgo.com/lite?q={query}")
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        with_params(
+            format!("https://lite.duckduckgo.com/lite?q={query}"),
+            &duckduckgo_params(options),
+        )
     }
 
     fn name(&self) -> String {
@@ -33,8 +184,11 @@ impl SearchEngine for DuckduckgoLite {
 pub struct DuckduckgoHtml {}
 
 impl SearchEngine for DuckduckgoHtml {
-    fn generate_url(&self, query: &str) -> String {
-        format!("https://html.duckduckgo.com/html?q={query}")
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        with_params(
+            format!("https://html.duckduckgo.com/html?q={query}"),
+            &duckduckgo_params(options),
+        )
     }
 
     fn name(&self) -> String {
@@ -46,8 +200,11 @@ impl SearchEngine for DuckduckgoHtml {
 pub struct DuckduckgoNoAi {}
 
 impl SearchEngine for DuckduckgoNoAi {
-    fn generate_url(&self, query: &str) -> String {
-        format!("https://noai.duckduckgo.com/html?q={query}")
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        with_params(
+            format!("https://noai.duckduckgo.com/html?q={query}"),
+            &duckduckgo_params(options),
+        )
     }
 
     fn name(&self) -> String {
@@ -59,8 +216,32 @@ impl SearchEngine for DuckduckgoNoAi {
 pub struct Google {}
 
 impl SearchEngine for Google {
-    fn generate_url(&self, query: &str) -> String {
-        format!("https://www.google.com/search?q={query}")
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        let safe_search = options.safe_search.map(|safe_search| match safe_search {
+            SafeSearch::Off => "off",
+            SafeSearch::Moderate | SafeSearch::Strict => "active",
+        });
+        let freshness = options.freshness.map(|freshness| match freshness {
+            Freshness::Day => "qdr:d",
+            Freshness::Week => "qdr:w",
+            Freshness::Month => "qdr:m",
+            Freshness::Year => "qdr:y",
+        });
+        // Google only distinguishes scripts for Chinese, e.g. `lang_zh-CN`
+        let language_restrict = options.language().map(|language| match language.as_str() {
+            "zh" => format!("lang_{}", options.locale.as_deref().unwrap_or("zh-CN")),
+            _ => format!("lang_{language}"),
+        });
+        with_params(
+            format!("https://www.google.com/search?q={query}"),
+            &[
+                ("hl", options.locale.clone()),
+                ("lr", language_restrict),
+                ("gl", options.region.clone()),
+                ("safe", safe_search.map(str::to_string)),
+                ("tbs", freshness.map(str::to_string)),
+            ],
+        )
     }
 
     fn name(&self) -> String {
@@ -72,8 +253,28 @@ impl SearchEngine for Google {
 pub struct Bing {}
 
 impl SearchEngine for Bing {
-    fn generate_url(&self, query: &str) -> String {
-        format!("https://www.bing.com/search?q={query}")
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        let safe_search = options.safe_search.map(|safe_search| match safe_search {
+            SafeSearch::Off => "off",
+            SafeSearch::Moderate => "moderate",
+            SafeSearch::Strict => "strict",
+        });
+        // Bing has no preset for the past year
+        let freshness = options.freshness.and_then(|freshness| match freshness {
+            Freshness::Day => Some("ex1:\"ez1\""),
+            Freshness::Week => Some("ex1:\"ez2\""),
+            Freshness::Month => Some("ex1:\"ez3\""),
+            Freshness::Year => None,
+        });
+        with_params(
+            format!("https://www.bing.com/search?q={query}"),
+            &[
+                ("setlang", options.locale.clone()),
+                ("cc", options.region.clone()),
+                ("adlt", safe_search.map(str::to_string)),
+                ("filters", freshness.map(str::to_string)),
+            ],
+        )
     }
 
     fn name(&self) -> String {
@@ -85,7 +286,7 @@ impl SearchEngine for Bing {
 pub struct Baidu {}
 
 impl SearchEngine for Baidu {
-    fn generate_url(&self, query: &str) -> String {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
         format!("https://www.baidu.com/s?wd={query}")
     }
 
@@ -98,7 +299,7 @@ impl SearchEngine for Baidu {
 pub struct Sogou {}
 
 impl SearchEngine for Sogou {
-    fn generate_url(&self, query: &str) -> String {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
         format!("https://www.sogou.com/web?query={query}")
     }
 
@@ -106,3 +307,176 @@ impl SearchEngine for Sogou {
         "sogou".to_string()
     }
 }
+
+#[derive(Clone, Debug)]
+pub struct Searxng {
+    base_url: String,
+}
+
+impl Searxng {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl SearchEngine for Searxng {
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        let safe_search = options.safe_search.map(|safe_search| match safe_search {
+            SafeSearch::Off => "0",
+            SafeSearch::Moderate => "1",
+            SafeSearch::Strict => "2",
+        });
+        with_params(
+            format!("{}/search?q={query}", self.base_url),
+            &[
+                ("categories", options.categories.clone()),
+                ("language", options.locale.clone()),
+                ("safesearch", safe_search.map(str::to_string)),
+                ("time_range", options.freshness.map(|freshness| freshness.as_str().to_string())),
+            ],
+        )
+    }
+
+    fn name(&self) -> String {
+        "searxng".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GithubCode {}
+
+impl SearchEngine for GithubCode {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
+        format!("https://github.com/search?q={query}&type=code")
+    }
+
+    fn name(&self) -> String {
+        "github-code".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GithubIssues {}
+
+impl SearchEngine for GithubIssues {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
+        format!("https://github.com/search?q={query}&type=issues")
+    }
+
+    fn name(&self) -> String {
+        "github-issues".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StackOverflow {}
+
+impl SearchEngine for StackOverflow {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
+        format!("https://stackoverflow.com/search?q={query}")
+    }
+
+    fn name(&self) -> String {
+        "stackoverflow".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DocsRs {}
+
+impl SearchEngine for DocsRs {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
+        format!("https://docs.rs/releases/search?query={query}")
+    }
+
+    fn name(&self) -> String {
+        "docs-rs".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CratesIo {}
+
+impl SearchEngine for CratesIo {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
+        format!("https://crates.io/search?q={query}")
+    }
+
+    fn name(&self) -> String {
+        "crates-io".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Mdn {}
+
+impl SearchEngine for Mdn {
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        // MDN is only fully translated into a handful of locales, English is always available
+        let locale = match options.language().as_deref() {
+            Some("de") => "de",
+            Some("es") => "es",
+            Some("fr") => "fr",
+            Some("ja") => "ja",
+            Some("ko") => "ko",
+            Some("ru") => "ru",
+            Some("zh") => "zh-CN",
+            _ => "en-US",
+        };
+        format!("https://developer.mozilla.org/{locale}/search?q={query}")
+    }
+
+    fn name(&self) -> String {
+        "mdn".to_string()
+    }
+}
+
+/// Wikipedia edition is fixed by `wikipedia-<code>`, otherwise it follows the locale and defaults to English.
+#[derive(Clone, Debug, Default)]
+pub struct Wikipedia {
+    language: Option<String>,
+}
+
+impl Wikipedia {
+    pub fn new(language: Option<&str>) -> Self {
+        Self {
+            language: language.map(|language| language.to_string()),
+        }
+    }
+}
+
+impl SearchEngine for Wikipedia {
+    fn generate_url(&self, query: &str, options: &SearchOptions) -> String {
+        let language = self
+            .language
+            .clone()
+            .or(options.language())
+            .filter(|language| {
+                !language.is_empty() && language.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+            .unwrap_or("en".to_string());
+        format!(
+            "https://{}.wikipedia.org/w/index.php?search={query}",
+            language.to_lowercase()
+        )
+    }
+
+    fn name(&self) -> String {
+        "wikipedia".to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Youtube {}
+
+impl SearchEngine for Youtube {
+    fn generate_url(&self, query: &str, _options: &SearchOptions) -> String {
+        format!("https://www.youtube.com/results?search_query={query}")
+    }
+
+    fn name(&self) -> String {
+        "youtube".to_string()
+    }
+}
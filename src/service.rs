@@ -1,3 +1,28 @@
 pub mod search;
+pub mod answer;
 pub mod auth;
-pub mod turnstile;
+pub mod cache;
+pub mod captcha;
+pub mod guild;
+pub mod local_account;
+pub mod results;
+pub mod quota;
+pub mod rate_limit;
+pub mod risk;
+pub mod session;
+pub mod single_flight;
+pub mod token;
+pub mod user;
+
+/// Empty in-memory database with every migration applied.
+#[cfg(test)]
+pub async fn test_pool() -> sqlx::SqlitePool {
+    // Every connection to `:memory:` opens its own database
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
@@ -0,0 +1,159 @@
+use async_trait::async_trait;
+use log::info;
+use thiserror::Error;
+
+use crate::{
+    llm::{strip_json_fence, LLMError, LLMPrompt, LargeLanguageModel},
+    model::results::{map_citations, Answer, SearchResult},
+};
+
+#[derive(Error, Debug)]
+pub enum AnswerError {
+    #[error("Error when querying AI")]
+    Ai(#[from] LLMError),
+    #[error("Failed to deserialize response")]
+    Deserialize(#[from] serde_json::Error),
+}
+
+#[derive(serde::Serialize)]
+struct AnswerRequest<'a> {
+    prompt: &'a str,
+    language: &'a str,
+    results: Vec<NumberedResult<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct NumberedResult<'a> {
+    id: usize,
+    title: &'a str,
+    url: &'a str,
+    snippet: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct AnswerResponse {
+    ranking: Vec<usize>,
+    summary: String,
+}
+
+#[async_trait]
+pub trait AnswerService: Send + Sync {
+    /// Re-rank `results` against the original `prompt` and summarize them with citations.
+    async fn answer(
+        &self,
+        prompt: &str,
+        language: &str,
+        results: Vec<SearchResult>,
+    ) -> Result<Answer, AnswerError>;
+}
+
+pub struct AnswerServiceImpl {
+    llm: Box<dyn LargeLanguageModel>,
+    llm_model: String,
+    prompt_template: String,
+    top_n: usize,
+}
+
+impl AnswerServiceImpl {
+    pub fn new(
+        llm: Box<dyn LargeLanguageModel>,
+        llm_model: String,
+        prompt_file: String,
+        top_n: usize,
+    ) -> Self {
+        let prompt_template = std::fs::read_to_string(prompt_file).expect("Failed to read answer prompt file");
+        Self {
+            llm,
+            llm_model,
+            prompt_template,
+            top_n,
+        }
+    }
+}
+
+#[async_trait]
+impl AnswerService for AnswerServiceImpl {
+    async fn answer(
+        &self,
+        prompt: &str,
+        language: &str,
+        mut results: Vec<SearchResult>,
+    ) -> Result<Answer, AnswerError> {
+        results.truncate(self.top_n);
+        if results.is_empty() {
+            return Ok(Answer {
+                summary: String::new(),
+                results,
+            });
+        }
+
+        let answer_request = AnswerRequest {
+            prompt,
+            language,
+            results: results
+                .iter()
+                .enumerate()
+                .map(|(index, result)| NumberedResult {
+                    id: index + 1,
+                    title: &result.title,
+                    url: &result.url,
+                    snippet: &result.snippet,
+                })
+                .collect(),
+        };
+
+        let contents = vec![
+            LLMPrompt::new("user", &self.prompt_template),
+            LLMPrompt::new("user", &serde_json::to_string_pretty(&answer_request)?),
+        ];
+        let content = self.llm.query(&self.llm_model, &contents).await?;
+        let response: AnswerResponse = serde_json::from_str(strip_json_fence(&content))?;
+
+        // Results keep their input ids as positions, ranked ones first
+        let mut order: Vec<usize> = Vec::with_capacity(results.len());
+        for id in response.ranking {
+            if (1..=results.len()).contains(&id) && !order.contains(&id) {
+                order.push(id);
+            }
+        }
+        let ranked = order.len();
+        for id in 1..=results.len() {
+            if !order.contains(&id) {
+                order.push(id);
+            }
+        }
+
+        info!("Answered `{}` from {} results, {} ranked relevant", prompt, results.len(), ranked);
+
+        let summary = renumber_citations(&response.summary, &order);
+        let results = order.iter().map(|id| results[id - 1].clone()).collect();
+        Ok(Answer { summary, results })
+    }
+}
+
+/// Rewrite `[id]` citations to the position of that result in `order`, dropping unknown ids.
+fn renumber_citations(summary: &str, order: &[usize]) -> String {
+    map_citations(summary, |id| {
+        Some(match order.iter().position(|ordered| *ordered == id) {
+            Some(position) => format!("[{}]", position + 1),
+            None => String::new(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renumbers_citations_to_ranked_positions() {
+        let summary = "Tokio is async [3], see also [1][2].";
+        assert_eq!(renumber_citations(summary, &[3, 1, 2]), "Tokio is async [1], see also [2][3].");
+    }
+
+    #[test]
+    fn drops_unknown_citations_and_keeps_other_brackets() {
+        let summary = "Unknown [7][2], not citations [x] [";
+        assert_eq!(renumber_citations(summary, &[2, 1]), "Unknown [1], not citations [x] [");
+    }
+}
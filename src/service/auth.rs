@@ -1,6 +1,13 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{distributions::Alphanumeric, Rng};
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,95 +16,577 @@ pub enum AuthError {
     Reqwest(#[from] reqwest::Error),
     #[error("Serde error: {0}")]
     Serde(#[from] serde_json::Error),
-    #[error("Discord API error: {0}")]
-    DiscordApiError(String),
+    #[error("Database error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("{provider} API error: {message}")]
+    ProviderApi { provider: String, message: String },
+    #[error("Identity is already linked to another user")]
+    IdentityTaken,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LoginAttemptError {
+    #[error("No login is in progress, it may have been completed already")]
+    Missing,
+    #[error("The login was started for {0}")]
+    ProviderMismatch(String),
+    #[error("The login attempt expired, please try again")]
+    Expired,
+    #[error("The login state doesn't match")]
+    StateMismatch,
+}
+
+/// Maximum duration between starting a login and the provider's callback
+const LOGIN_ATTEMPT_TTL_SECONDS: i64 = 600;
+
+/// A login in progress, kept in the session between the redirect to the provider and its callback.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoginAttempt {
+    pub provider: String,
+    /// Random `state` the provider hands back to the callback
+    pub state: String,
+    /// PKCE code verifier, only its SHA-256 challenge is sent with the authorization request
+    pub code_verifier: String,
+    /// Unix timestamp
+    pub started_at: i64,
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+impl LoginAttempt {
+    pub fn new(provider: &str) -> Self {
+        Self {
+            provider: provider.to_string(),
+            state: random_string(32),
+            code_verifier: random_string(64),
+            started_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    pub fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+
+    /// Check that a callback belongs to this attempt.
+    pub fn verify(&self, provider: &str, state: &str) -> Result<(), LoginAttemptError> {
+        if self.provider != provider {
+            return Err(LoginAttemptError::ProviderMismatch(self.provider.clone()));
+        }
+        if chrono::Utc::now().timestamp() - self.started_at > LOGIN_ATTEMPT_TTL_SECONDS {
+            return Err(LoginAttemptError::Expired);
+        }
+        // Compare in constant time
+        let matches = self.state.len() == state.len()
+            && self
+                .state
+                .bytes()
+                .zip(state.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        if !matches {
+            return Err(LoginAttemptError::StateMismatch);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug)]
-pub struct DiscordTokenResponse {
+pub struct TokenResponse {
     pub access_token: String,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct DiscordUser {
-    pub id: String,
+/// A user as reported by an identity provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProviderUser {
+    /// Stable id of the user at the provider
+    pub subject: String,
     pub username: String,
 }
 
-#[async_trait]
-pub trait AuthService: Send + Sync {
-    fn get_discord_auth_url(&self) -> String;
-    async fn exchange_code_for_token(&self, code: &str) -> Result<String, AuthError>;
-    async fn get_discord_user(&self, access_token: &str) -> Result<DiscordUser, AuthError>;
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ProviderInfo {
+    pub name: String,
+    pub display_name: String,
 }
 
-pub struct AuthServiceImpl {
-    discord_client_id: String,
-    discord_client_secret: String,
-    discord_redirect_uri: String,
-    http_client: Client,
+/// OAuth2 client credentials of a provider.
+#[derive(Clone, Debug)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
 }
 
-impl AuthServiceImpl {
-    pub fn new(
-        discord_client_id: String,
-        discord_client_secret: String,
-        discord_redirect_uri: String,
-    ) -> Self {
-        Self {
-            discord_client_id,
-            discord_client_secret,
-            discord_redirect_uri,
-            http_client: Client::new(),
-        }
-    }
+/// Endpoints of an authorization code flow.
+#[derive(Deserialize, Clone, Debug)]
+pub struct OAuthEndpoints {
+    #[serde(rename = "authorization_endpoint")]
+    pub authorize_url: String,
+    #[serde(rename = "token_endpoint")]
+    pub token_url: String,
+    #[serde(rename = "userinfo_endpoint")]
+    pub user_url: String,
 }
 
-#[async_trait]
-impl AuthService for AuthServiceImpl {
-    fn get_discord_auth_url(&self) -> String {
+impl OAuthClient {
+    fn authorize_url(&self, endpoints: &OAuthEndpoints, scope: &str, attempt: &LoginAttempt) -> String {
         format!(
-            "https://discord.com/api/oauth2/authorize?client_id={}&redirect_uri={}&response_type=code&scope=identify",
-            self.discord_client_id, self.discord_redirect_uri
+            "{}?client_id={}&redirect_uri={}&response_type=code&scope={}&state={}&code_challenge={}&code_challenge_method=S256",
+            endpoints.authorize_url,
+            urlencoding::encode(&self.client_id),
+            urlencoding::encode(&self.redirect_uri),
+            urlencoding::encode(scope),
+            attempt.state,
+            attempt.code_challenge()
         )
     }
 
-    async fn exchange_code_for_token(&self, code: &str) -> Result<String, AuthError> {
+    async fn exchange_code(
+        &self,
+        http_client: &Client,
+        provider: &str,
+        endpoints: &OAuthEndpoints,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<String, AuthError> {
         let params = [
-            ("client_id", &self.discord_client_id),
-            ("client_secret", &self.discord_client_secret),
-            ("grant_type", &"authorization_code".to_string()),
-            ("code", &code.to_string()),
-            ("redirect_uri", &self.discord_redirect_uri),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("code_verifier", code_verifier),
         ];
 
-        let response = self
-            .http_client
-            .post("https://discord.com/api/oauth2/token")
+        let response = http_client
+            .post(&endpoints.token_url)
+            .header("Accept", "application/json")
             .form(&params)
             .send()
-            .await?
-            .json::<DiscordTokenResponse>()
             .await?;
-
+        let response: TokenResponse = json_response(provider, response).await?;
         Ok(response.access_token)
     }
+}
+
+/// Fetch a provider API with the user's access token.
+async fn fetch_user(
+    http_client: &Client,
+    provider: &str,
+    user_url: &str,
+    access_token: &str,
+) -> Result<Value, AuthError> {
+    let response = http_client
+        .get(user_url)
+        .bearer_auth(access_token)
+        // GitHub rejects requests without a user agent
+        .header("User-Agent", "ai-search-bangs")
+        .header("Accept", "application/json")
+        .send()
+        .await?;
+    json_response(provider, response).await
+}
+
+async fn json_response<T: serde::de::DeserializeOwned>(
+    provider: &str,
+    response: reqwest::Response,
+) -> Result<T, AuthError> {
+    if !response.status().is_success() {
+        let message = response.text().await?;
+        log::error!("{} API error: {}", provider, message);
+        return Err(AuthError::ProviderApi {
+            provider: provider.to_string(),
+            message,
+        });
+    }
+    Ok(serde_json::from_str(&response.text().await?)?)
+}
+
+/// Read a string or numeric field of a user object.
+fn user_field(user: &Value, field: &str) -> Option<String> {
+    match user.get(field)? {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn provider_user(
+    provider: &str,
+    user: &Value,
+    subject_field: &str,
+    username_fields: &[&str],
+) -> Result<ProviderUser, AuthError> {
+    let subject = user_field(user, subject_field).ok_or_else(|| AuthError::ProviderApi {
+        provider: provider.to_string(),
+        message: format!("User is missing the `{subject_field}` field"),
+    })?;
+    let username = username_fields
+        .iter()
+        .find_map(|field| user_field(user, field))
+        .unwrap_or_else(|| subject.clone());
+    Ok(ProviderUser { subject, username })
+}
+
+#[async_trait]
+pub trait OAuthProvider: Send + Sync {
+    /// Name used in `/auth/{provider}/...` routes and stored with identities
+    fn name(&self) -> &str;
+    fn display_name(&self) -> &str;
+    fn authorize_url(&self, attempt: &LoginAttempt) -> String;
+    async fn exchange_code_for_token(&self, code: &str, code_verifier: &str) -> Result<String, AuthError>;
+    async fn get_user(&self, access_token: &str) -> Result<ProviderUser, AuthError>;
+}
+
+pub struct Discord {
+    client: OAuthClient,
+    endpoints: OAuthEndpoints,
+    scope: &'static str,
+    http_client: Client,
+}
+
+impl Discord {
+    /// With `guild_members` the login may also read the user's guild memberships.
+    pub fn new(client: OAuthClient, guild_members: bool) -> Self {
+        Self {
+            client,
+            scope: if guild_members {
+                "identify guilds.members.read"
+            } else {
+                "identify"
+            },
+            endpoints: OAuthEndpoints {
+                authorize_url: "https://discord.com/api/oauth2/authorize".to_string(),
+                token_url: "https://discord.com/api/oauth2/token".to_string(),
+                user_url: "https://discord.com/api/users/@me".to_string(),
+            },
+            http_client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for Discord {
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    fn display_name(&self) -> &str {
+        "Discord"
+    }
+
+    fn authorize_url(&self, attempt: &LoginAttempt) -> String {
+        self.client.authorize_url(&self.endpoints, self.scope, attempt)
+    }
+
+    async fn exchange_code_for_token(&self, code: &str, code_verifier: &str) -> Result<String, AuthError> {
+        self.client
+            .exchange_code(&self.http_client, self.name(), &self.endpoints, code, code_verifier)
+            .await
+    }
+
+    async fn get_user(&self, access_token: &str) -> Result<ProviderUser, AuthError> {
+        let user = fetch_user(&self.http_client, self.name(), &self.endpoints.user_url, access_token).await?;
+        provider_user(self.name(), &user, "id", &["username"])
+    }
+}
+
+pub struct Github {
+    client: OAuthClient,
+    endpoints: OAuthEndpoints,
+    http_client: Client,
+}
+
+impl Github {
+    pub fn new(client: OAuthClient) -> Self {
+        Self {
+            client,
+            endpoints: OAuthEndpoints {
+                authorize_url: "https://github.com/login/oauth/authorize".to_string(),
+                token_url: "https://github.com/login/oauth/access_token".to_string(),
+                user_url: "https://api.github.com/user".to_string(),
+            },
+            http_client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for Github {
+    fn name(&self) -> &str {
+        "github"
+    }
+
+    fn display_name(&self) -> &str {
+        "GitHub"
+    }
+
+    fn authorize_url(&self, attempt: &LoginAttempt) -> String {
+        self.client.authorize_url(&self.endpoints, "read:user", attempt)
+    }
+
+    async fn exchange_code_for_token(&self, code: &str, code_verifier: &str) -> Result<String, AuthError> {
+        self.client
+            .exchange_code(&self.http_client, self.name(), &self.endpoints, code, code_verifier)
+            .await
+    }
+
+    async fn get_user(&self, access_token: &str) -> Result<ProviderUser, AuthError> {
+        let user = fetch_user(&self.http_client, self.name(), &self.endpoints.user_url, access_token).await?;
+        provider_user(self.name(), &user, "id", &["login"])
+    }
+}
+
+/// OpenID Connect provider, identities are keyed by the `sub` claim of the userinfo endpoint.
+pub struct Oidc {
+    name: String,
+    display_name: String,
+    client: OAuthClient,
+    endpoints: OAuthEndpoints,
+    http_client: Client,
+}
+
+impl Oidc {
+    pub fn new(name: &str, display_name: &str, client: OAuthClient, endpoints: OAuthEndpoints) -> Self {
+        Self {
+            name: name.to_string(),
+            display_name: display_name.to_string(),
+            client,
+            endpoints,
+            http_client: Client::new(),
+        }
+    }
 
-    async fn get_discord_user(&self, access_token: &str) -> Result<DiscordUser, AuthError> {
-        let response = self
-            .http_client
-            .get("https://discord.com/api/users/@me")
-            .bearer_auth(access_token)
+    pub fn google(client: OAuthClient) -> Self {
+        Self::new(
+            "google",
+            "Google",
+            client,
+            OAuthEndpoints {
+                authorize_url: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+                token_url: "https://oauth2.googleapis.com/token".to_string(),
+                user_url: "https://openidconnect.googleapis.com/v1/userinfo".to_string(),
+            },
+        )
+    }
+
+    /// Read the endpoints from `{issuer}/.well-known/openid-configuration`.
+    pub async fn discover(
+        name: &str,
+        display_name: &str,
+        issuer: &str,
+        client: OAuthClient,
+    ) -> Result<Self, AuthError> {
+        let http_client = Client::new();
+        let response = http_client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
             .send()
             .await?;
+        let endpoints: OAuthEndpoints = json_response(name, response).await?;
+        Ok(Self::new(name, display_name, client, endpoints))
+    }
+}
+
+#[async_trait]
+impl OAuthProvider for Oidc {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn display_name(&self) -> &str {
+        &self.display_name
+    }
 
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            log::error!("Discord API error: {}", error_text);
-            return Err(AuthError::DiscordApiError(error_text));
+    fn authorize_url(&self, attempt: &LoginAttempt) -> String {
+        self.client.authorize_url(&self.endpoints, "openid profile email", attempt)
+    }
+
+    async fn exchange_code_for_token(&self, code: &str, code_verifier: &str) -> Result<String, AuthError> {
+        self.client
+            .exchange_code(&self.http_client, &self.name, &self.endpoints, code, code_verifier)
+            .await
+    }
+
+    async fn get_user(&self, access_token: &str) -> Result<ProviderUser, AuthError> {
+        let user = fetch_user(&self.http_client, &self.name, &self.endpoints.user_url, access_token).await?;
+        provider_user(&self.name, &user, "sub", &["preferred_username", "name", "email"])
+    }
+}
+
+#[async_trait]
+pub trait AuthService: Send + Sync {
+    fn provider(&self, name: &str) -> Option<&dyn OAuthProvider>;
+    fn providers(&self) -> Vec<ProviderInfo>;
+    /// Find or create the user of an identity. With `current_user` set, the identity is linked to that user.
+    async fn sign_in(
+        &self,
+        provider: &str,
+        user: &ProviderUser,
+        current_user: Option<i64>,
+    ) -> Result<i64, AuthError>;
+    /// The user an identity is linked to
+    async fn linked_user(&self, provider: &str, subject: &str) -> Result<Option<i64>, AuthError>;
+    /// Providers linked to a user
+    async fn identities(&self, user_id: i64) -> Result<Vec<String>, AuthError>;
+}
+
+pub struct AuthServiceImpl {
+    pool: SqlitePool,
+    providers: HashMap<String, Box<dyn OAuthProvider>>,
+}
+
+impl AuthServiceImpl {
+    pub fn new(pool: SqlitePool, providers: Vec<Box<dyn OAuthProvider>>) -> Self {
+        Self {
+            pool,
+            providers: providers
+                .into_iter()
+                .map(|provider| (provider.name().to_string(), provider))
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthService for AuthServiceImpl {
+    fn provider(&self, name: &str) -> Option<&dyn OAuthProvider> {
+        self.providers.get(name).map(|provider| provider.as_ref())
+    }
+
+    fn providers(&self) -> Vec<ProviderInfo> {
+        let mut providers: Vec<ProviderInfo> = self
+            .providers
+            .values()
+            .map(|provider| ProviderInfo {
+                name: provider.name().to_string(),
+                display_name: provider.display_name().to_string(),
+            })
+            .collect();
+        providers.sort_by(|a, b| a.name.cmp(&b.name));
+        providers
+    }
+
+    async fn sign_in(
+        &self,
+        provider: &str,
+        user: &ProviderUser,
+        current_user: Option<i64>,
+    ) -> Result<i64, AuthError> {
+        let mut transaction = self.pool.begin().await?;
+        let linked_user = sqlx::query_scalar!(
+            "SELECT user_id FROM identities WHERE provider = ? AND subject = ?",
+            provider,
+            user.subject
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        let user_id = match (linked_user, current_user) {
+            (Some(linked_user), Some(current_user)) if linked_user != current_user => {
+                return Err(AuthError::IdentityTaken);
+            }
+            (Some(linked_user), _) => linked_user,
+            (None, Some(current_user)) => current_user,
+            (None, None) => {
+                let now = chrono::Utc::now().naive_utc();
+                let today = now.date();
+                sqlx::query!(
+                    "INSERT INTO users (username, last_request_date, created_at) VALUES (?, ?, ?)",
+                    user.username,
+                    today,
+                    now
+                )
+                .execute(&mut *transaction)
+                .await?
+                .last_insert_rowid()
+            }
+        };
+
+        sqlx::query!(
+            "INSERT INTO identities (provider, subject, user_id, username) VALUES (?, ?, ?, ?)
+             ON CONFLICT(provider, subject) DO UPDATE SET username = excluded.username",
+            provider,
+            user.subject,
+            user_id,
+            user.username
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(user_id)
+    }
+
+    async fn linked_user(&self, provider: &str, subject: &str) -> Result<Option<i64>, AuthError> {
+        let user_id = sqlx::query_scalar!(
+            "SELECT user_id FROM identities WHERE provider = ? AND subject = ?",
+            provider,
+            subject
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user_id)
+    }
+
+    async fn identities(&self, user_id: i64) -> Result<Vec<String>, AuthError> {
+        let providers = sqlx::query_scalar!(
+            "SELECT provider FROM identities WHERE user_id = ? ORDER BY created_at",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(providers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attempt(started_at: i64) -> LoginAttempt {
+        LoginAttempt {
+            provider: "github".to_string(),
+            state: "abcdef".to_string(),
+            code_verifier: "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string(),
+            started_at,
         }
+    }
+
+    #[test]
+    fn accepts_matching_callback() {
+        let attempt = attempt(chrono::Utc::now().timestamp());
+        assert_eq!(attempt.verify("github", "abcdef"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_other_state_or_provider() {
+        let attempt = attempt(chrono::Utc::now().timestamp());
+        assert_eq!(attempt.verify("github", "abcdeg"), Err(LoginAttemptError::StateMismatch));
+        assert_eq!(attempt.verify("github", "abc"), Err(LoginAttemptError::StateMismatch));
+        assert_eq!(
+            attempt.verify("discord", "abcdef"),
+            Err(LoginAttemptError::ProviderMismatch("github".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_expired_attempt() {
+        let attempt = attempt(chrono::Utc::now().timestamp() - LOGIN_ATTEMPT_TTL_SECONDS - 1);
+        assert_eq!(attempt.verify("github", "abcdef"), Err(LoginAttemptError::Expired));
+    }
 
-        let user = response.json::<DiscordUser>().await?;
-        Ok(user)
+    #[test]
+    fn code_challenge_is_s256() {
+        // Example from RFC 7636, appendix B
+        let attempt = attempt(0);
+        assert_eq!(attempt.code_challenge(), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
     }
 }
@@ -0,0 +1,910 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use log::{info, warn};
+use lru::LruCache;
+use sqlx::SqlitePool;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{llm::EmbeddingModel, model::search::SearchOptions, service::search::canonical_engine};
+
+/// Cache key form of a prompt: NFKC normalized, case-folded, trimmed and with whitespace collapsed.
+pub fn normalize_prompt(prompt: &str) -> String {
+    prompt
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reuses entries of similar prompts, compared by the cosine similarity of their embeddings.
+pub struct SemanticCache {
+    pub model: Box<dyn EmbeddingModel>,
+    pub model_id: String,
+    /// Minimum similarity for an entry to be reused, between 0 and 1
+    pub threshold: f32,
+    /// Most recently used entries compared on a miss, bounds the work of every lookup
+    pub max_candidates: i64,
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+fn encode_embedding(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn decode_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+/// How long generated queries stay in the `cache` table.
+#[derive(Clone, Debug, Default)]
+pub struct CachePolicy {
+    /// `None` keeps entries forever
+    pub ttl: Option<Duration>,
+    /// Per-engine TTL overriding `ttl`, keyed by the canonical engine name. Routed queries use the TTL of the engine
+    /// they were routed to.
+    pub engine_ttl: HashMap<String, Option<Duration>>,
+    /// Upper bound of rows kept by the purge task, oldest are removed first
+    pub max_entries: Option<i64>,
+    /// How long the memory tier serves an entry before reading it from SQLite again, so changes made by other
+    /// processes such as the CLI show up. `None` keeps it until the entry expires.
+    pub memory_ttl: Option<Duration>,
+}
+
+impl CachePolicy {
+    /// TTL of an entry cached under `cache_engine`, e.g. `auto:dev`, and routed to `routed_engine` if any.
+    pub fn ttl_for(&self, cache_engine: &str, routed_engine: Option<&str>) -> Option<Duration> {
+        let engine = match routed_engine {
+            Some(routed_engine) => canonical_engine(routed_engine),
+            None => canonical_engine(cache_engine.split(':').next().unwrap_or(cache_engine)),
+        };
+        self.engine_ttl.get(&engine).copied().unwrap_or(self.ttl)
+    }
+}
+
+/// A generated query and how it was generated, urls are built from it on every read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedQuery {
+    /// Url at generation time, only used for entries cached without their query
+    pub url: String,
+    pub generated_query: Option<String>,
+    /// Engine the LLM routed to, if routing was enabled
+    pub routed_engine: Option<String>,
+    /// Options the LLM detected in the prompt
+    pub options: SearchOptions,
+    pub llm_model: Option<String>,
+    pub prompt_version: Option<String>,
+}
+
+fn decode_options(options: Option<String>) -> SearchOptions {
+    options
+        .and_then(|options| serde_json::from_str(&options).ok())
+        .unwrap_or_default()
+}
+
+/// `(query_prompt, search_engine, language)` as stored in the `cache` table
+pub type CacheKey = (String, String, String);
+
+#[derive(Clone, Debug)]
+struct MemoryEntry {
+    entry: CachedQuery,
+    expires_at: Option<NaiveDateTime>,
+    /// Key of the row the entry was read from, differs from the memory key for semantic hits
+    row_key: CacheKey,
+}
+
+impl MemoryEntry {
+    fn is_expired(&self, now: NaiveDateTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Default)]
+struct TierCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TierCounters {
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> TierStats {
+        TierStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct TierStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct CacheStats {
+    pub memory: TierStats,
+    pub memory_entries: usize,
+    pub sqlite: TierStats,
+    pub semantic: Option<TierStats>,
+}
+
+/// Two tier cache of generated queries, a bounded in-process LRU in front of the `cache` table.
+pub struct QueryCache {
+    pool: SqlitePool,
+    policy: CachePolicy,
+    /// `None` when the memory tier is disabled
+    memory: Option<Mutex<LruCache<CacheKey, MemoryEntry>>>,
+    memory_counters: TierCounters,
+    sqlite_counters: TierCounters,
+    semantic: Option<SemanticCache>,
+    semantic_counters: TierCounters,
+    /// Hits not yet written to `hit_count` and `last_used_at`
+    pending_hits: Mutex<HashMap<CacheKey, (i64, NaiveDateTime)>>,
+}
+
+impl QueryCache {
+    pub fn new(
+        pool: SqlitePool,
+        policy: CachePolicy,
+        memory_entries: usize,
+        semantic: Option<SemanticCache>,
+    ) -> Self {
+        Self {
+            pool,
+            policy,
+            memory: NonZeroUsize::new(memory_entries).map(|capacity| Mutex::new(LruCache::new(capacity))),
+            memory_counters: TierCounters::default(),
+            sqlite_counters: TierCounters::default(),
+            semantic,
+            semantic_counters: TierCounters::default(),
+            pending_hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            memory: self.memory_counters.stats(),
+            memory_entries: self
+                .memory
+                .as_ref()
+                .map(|memory| memory.lock().unwrap().len())
+                .unwrap_or(0),
+            sqlite: self.sqlite_counters.stats(),
+            semantic: self.semantic.as_ref().map(|_| self.semantic_counters.stats()),
+        }
+    }
+
+    fn memory_get(&self, key: &CacheKey, now: NaiveDateTime) -> Option<(CachedQuery, CacheKey)> {
+        let memory = self.memory.as_ref()?;
+        let mut memory = memory.lock().unwrap();
+        let cached = match memory.get(key) {
+            Some(cached) if cached.is_expired(now) => {
+                memory.pop(key);
+                None
+            }
+            Some(cached) => Some((cached.entry.clone(), cached.row_key.clone())),
+            None => None,
+        };
+        self.memory_counters.record(cached.is_some());
+        cached
+    }
+
+    fn clear_memory(&self) {
+        if let Some(memory) = &self.memory {
+            memory.lock().unwrap().clear();
+        }
+    }
+
+    fn record_hit(&self, key: CacheKey, now: NaiveDateTime) {
+        let mut pending_hits = self.pending_hits.lock().unwrap();
+        let pending = pending_hits.entry(key).or_insert((0, now));
+        pending.0 += 1;
+        pending.1 = now;
+    }
+
+    /// Write pending hits to `hit_count` and `last_used_at`.
+    pub async fn flush_hits(&self) -> Result<(), sqlx::Error> {
+        let pending_hits = std::mem::take(&mut *self.pending_hits.lock().unwrap());
+        for ((query_prompt, search_engine, language), (hits, last_used_at)) in pending_hits {
+            sqlx::query!(
+                "UPDATE cache SET hit_count = hit_count + ?, last_used_at = ?
+                 WHERE query_prompt = ? AND search_engine = ? AND language = ?",
+                hits,
+                last_used_at,
+                query_prompt,
+                search_engine,
+                language
+            )
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn memory_put(&self, key: CacheKey, row_key: CacheKey, entry: CachedQuery, expires_at: Option<NaiveDateTime>) {
+        if let Some(memory) = &self.memory {
+            let reread_at = self.policy.memory_ttl.map(|ttl| Utc::now().naive_utc() + ttl);
+            let expires_at = match (expires_at, reread_at) {
+                (Some(expires_at), Some(reread_at)) => Some(expires_at.min(reread_at)),
+                (expires_at, reread_at) => expires_at.or(reread_at),
+            };
+            memory.lock().unwrap().put(
+                key,
+                MemoryEntry {
+                    entry,
+                    expires_at,
+                    row_key,
+                },
+            );
+        }
+    }
+
+    /// Look up an entry in memory, then in SQLite. Expired ones are treated as missing.
+    pub async fn get(
+        &self,
+        query_prompt: &str,
+        cache_engine: &str,
+        language: &str,
+    ) -> Result<Option<CachedQuery>, sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        let key = (query_prompt.to_string(), cache_engine.to_string(), language.to_string());
+        if let Some((cached, row_key)) = self.memory_get(&key, now) {
+            self.record_hit(row_key, now);
+            return Ok(Some(cached));
+        }
+
+        let record = sqlx::query!(
+            "SELECT url, generated_query, routed_engine, options, llm_model, prompt_version, expires_at, pinned FROM cache
+             WHERE query_prompt = ? AND search_engine = ? AND language = ?
+               AND (pinned OR expires_at IS NULL OR expires_at > ?)",
+            query_prompt,
+            cache_engine,
+            language,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        self.sqlite_counters.record(record.is_some());
+
+        Ok(record.map(|record| {
+            let cached = CachedQuery {
+                url: record.url,
+                generated_query: record.generated_query,
+                routed_engine: record.routed_engine,
+                options: decode_options(record.options),
+                llm_model: record.llm_model,
+                prompt_version: record.prompt_version,
+            };
+            let expires_at = if record.pinned { None } else { record.expires_at };
+            self.memory_put(key.clone(), key.clone(), cached.clone(), expires_at);
+            self.record_hit(key, now);
+            cached
+        }))
+    }
+
+    /// Embed a prompt for semantic lookups, `None` when disabled or when the embedding failed.
+    pub async fn embed(&self, query_prompt: &str) -> Option<Vec<f32>> {
+        let semantic = self.semantic.as_ref()?;
+        match semantic.model.embed(&semantic.model_id, query_prompt).await {
+            Ok(embedding) => Some(embedding),
+            Err(e) => {
+                warn!("Failed to embed prompt `{}`: {}", query_prompt, e);
+                None
+            }
+        }
+    }
+
+    /// Find the most similar prompt cached for the same engine and language.
+    /// A hit is remembered in the memory tier under `query_prompt`, its hits are counted for the matched entry.
+    pub async fn get_similar(
+        &self,
+        query_prompt: &str,
+        cache_engine: &str,
+        language: &str,
+        embedding: &[f32],
+    ) -> Result<Option<CachedQuery>, sqlx::Error> {
+        let Some(semantic) = &self.semantic else {
+            return Ok(None);
+        };
+
+        let now = Utc::now().naive_utc();
+        let candidates = sqlx::query!(
+            "SELECT query_prompt, url, generated_query, routed_engine, options, llm_model, prompt_version, expires_at,
+                    pinned, embedding
+             FROM cache
+             WHERE search_engine = ? AND language = ? AND embedding IS NOT NULL
+               AND (pinned OR expires_at IS NULL OR expires_at > ?)
+             ORDER BY COALESCE(last_used_at, created_at) DESC
+             LIMIT ?",
+            cache_engine,
+            language,
+            now,
+            semantic.max_candidates
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let best = candidates
+            .into_iter()
+            .filter_map(|candidate| {
+                let similarity = cosine_similarity(embedding, &decode_embedding(candidate.embedding.as_deref()?));
+                Some((similarity, candidate))
+            })
+            .filter(|(similarity, _)| *similarity >= semantic.threshold)
+            .max_by(|(a, _), (b, _)| a.total_cmp(b));
+        self.semantic_counters.record(best.is_some());
+
+        Ok(best.map(|(similarity, candidate)| {
+            info!(
+                "Semantic cache hit for `{}` using `{}` (similarity {:.3})",
+                query_prompt, candidate.query_prompt, similarity
+            );
+            let cached = CachedQuery {
+                url: candidate.url,
+                generated_query: candidate.generated_query,
+                routed_engine: candidate.routed_engine,
+                options: decode_options(candidate.options),
+                llm_model: candidate.llm_model,
+                prompt_version: candidate.prompt_version,
+            };
+            let expires_at = if candidate.pinned { None } else { candidate.expires_at };
+            let row_key = (candidate.query_prompt, cache_engine.to_string(), language.to_string());
+            self.memory_put(
+                (query_prompt.to_string(), cache_engine.to_string(), language.to_string()),
+                row_key.clone(),
+                cached.clone(),
+                expires_at,
+            );
+            self.record_hit(row_key, now);
+            cached
+        }))
+    }
+
+    /// Store an entry, replacing an expired one with the same key.
+    pub async fn insert(
+        &self,
+        query_prompt: &str,
+        cache_engine: &str,
+        language: &str,
+        entry: &CachedQuery,
+        embedding: Option<&[f32]>,
+    ) -> Result<(), sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        let expires_at: Option<NaiveDateTime> = self
+            .policy
+            .ttl_for(cache_engine, entry.routed_engine.as_deref())
+            .map(|ttl| now + ttl);
+        let embedding = embedding.map(encode_embedding);
+        let options = (!entry.options.is_empty())
+            .then(|| serde_json::to_string(&entry.options).ok())
+            .flatten();
+        sqlx::query!(
+            "INSERT INTO cache (query_prompt, search_engine, language, url, generated_query, routed_engine, options,
+                                llm_model, prompt_version, created_at, expires_at, embedding)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(query_prompt, search_engine, language) DO UPDATE SET
+                url = excluded.url,
+                generated_query = excluded.generated_query,
+                routed_engine = excluded.routed_engine,
+                options = excluded.options,
+                llm_model = excluded.llm_model,
+                prompt_version = excluded.prompt_version,
+                created_at = excluded.created_at,
+                expires_at = excluded.expires_at,
+                embedding = excluded.embedding",
+            query_prompt,
+            cache_engine,
+            language,
+            entry.url,
+            entry.generated_query,
+            entry.routed_engine,
+            options,
+            entry.llm_model,
+            entry.prompt_version,
+            now,
+            expires_at,
+            embedding
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let key = (query_prompt.to_string(), cache_engine.to_string(), language.to_string());
+        self.memory_put(key.clone(), key, entry.clone(), expires_at);
+        Ok(())
+    }
+
+    /// Recompute the expiry of every unpinned entry from its creation time and the current TTLs, so entries cached
+    /// before expiry was stored, or under other TTLs, follow the policy. Returns the number of changed entries.
+    pub async fn apply_policy(&self) -> Result<u64, sqlx::Error> {
+        let seconds = |ttl: Option<Duration>| ttl.map(|ttl| ttl.num_seconds());
+        let default_ttl = seconds(self.policy.ttl);
+        let engine_ttl = serde_json::to_string(
+            &self
+                .policy
+                .engine_ttl
+                .iter()
+                .map(|(engine, ttl)| (engine, seconds(*ttl)))
+                .collect::<HashMap<_, _>>(),
+        )
+        .unwrap_or_default();
+        // Same engine as `CachePolicy::ttl_for`, rows routed before engine names were canonical fall back to the
+        // default TTL. An override of `null` keeps entries forever.
+        let updated = sqlx::query!(
+            r#"UPDATE cache SET expires_at = policy.expires_at
+               FROM (
+                   SELECT id, CASE WHEN ttl IS NULL THEN NULL ELSE datetime(created_at, '+' || ttl || ' seconds') END
+                          AS expires_at
+                   FROM (
+                       SELECT id, created_at,
+                              CASE WHEN json_type(?1, '$."' || engine || '"') IS NULL THEN ?2
+                                   ELSE json_extract(?1, '$."' || engine || '"') END AS ttl
+                       FROM (
+                           SELECT rowid AS id, created_at,
+                                  COALESCE(routed_engine, CASE WHEN instr(search_engine, ':') > 0
+                                      THEN substr(search_engine, 1, instr(search_engine, ':') - 1)
+                                      ELSE search_engine END) AS engine
+                           FROM cache WHERE NOT pinned
+                       )
+                   )
+               ) AS policy
+               WHERE cache.rowid = policy.id AND cache.expires_at IS NOT policy.expires_at"#,
+            engine_ttl,
+            default_ttl
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if updated > 0 {
+            self.clear_memory();
+        }
+        Ok(updated)
+    }
+
+    /// Remove expired entries and trim the table to `max_entries`, returns the number of removed rows.
+    /// Pinned entries are kept, the memory tier drops the same entries.
+    pub async fn purge(&self) -> Result<u64, sqlx::Error> {
+        self.flush_hits().await?;
+        self.apply_policy().await?;
+        let now = Utc::now().naive_utc();
+        let expired = sqlx::query!(
+            "DELETE FROM cache WHERE NOT pinned AND expires_at IS NOT NULL AND expires_at <= ?",
+            now
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        let mut trimmed = 0;
+        if let Some(max_entries) = self.policy.max_entries {
+            trimmed = sqlx::query!(
+                "DELETE FROM cache WHERE rowid IN (
+                    SELECT rowid FROM cache WHERE NOT pinned ORDER BY created_at ASC
+                    LIMIT MAX((SELECT COUNT(*) FROM cache) - ?, 0)
+                 )",
+                max_entries
+            )
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        }
+
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+            if trimmed > 0 {
+                // The trimmed keys aren't known here, start the memory tier over
+                memory.clear();
+            } else {
+                let expired_keys: Vec<CacheKey> = memory
+                    .iter()
+                    .filter(|(_, cached)| cached.is_expired(now))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in expired_keys {
+                    memory.pop(&key);
+                }
+            }
+        }
+
+        info!(
+            "Purged {} cache entries ({} expired, {} over the size limit)",
+            expired + trimmed,
+            expired,
+            trimmed
+        );
+        Ok(expired + trimmed)
+    }
+
+    /// List entries matching `filter`, most recently created first.
+    pub async fn list(&self, filter: &CacheFilter, limit: i64, offset: i64) -> Result<Vec<CacheEntry>, sqlx::Error> {
+        self.flush_hits().await?;
+        let created_before = filter.created_before();
+        let entries = sqlx::query_as!(
+            CacheEntry,
+            r#"SELECT rowid AS "id!: i64", query_prompt, search_engine, language, url, generated_query, routed_engine,
+                      llm_model, prompt_version, created_at AS "created_at!: NaiveDateTime", expires_at,
+                      hit_count, last_used_at, pinned
+               FROM cache
+               WHERE (? IS NULL OR query_prompt LIKE ?)
+                 AND (? IS NULL OR search_engine = ?)
+                 AND (? IS NULL OR created_at < ?)
+               ORDER BY created_at DESC
+               LIMIT ? OFFSET ?"#,
+            filter.prompt_pattern,
+            filter.prompt_pattern,
+            filter.engine,
+            filter.engine,
+            created_before,
+            created_before,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(entries)
+    }
+
+    /// Delete entries matching `filter`, returns the number of removed rows.
+    pub async fn invalidate(&self, filter: &CacheFilter) -> Result<u64, sqlx::Error> {
+        let created_before = filter.created_before();
+        let removed = sqlx::query!(
+            "DELETE FROM cache
+             WHERE (? IS NULL OR query_prompt LIKE ?)
+               AND (? IS NULL OR search_engine = ?)
+               AND (? IS NULL OR created_at < ?)
+               AND (? OR NOT pinned)",
+            filter.prompt_pattern,
+            filter.prompt_pattern,
+            filter.engine,
+            filter.engine,
+            created_before,
+            created_before,
+            filter.include_pinned
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        self.clear_memory();
+        info!("Invalidated {} cache entries matching {:?}", removed, filter);
+        Ok(removed)
+    }
+
+    /// Pin or unpin an entry, pinned entries never expire. Returns whether the entry exists.
+    pub async fn set_pinned(&self, id: i64, pinned: bool) -> Result<bool, sqlx::Error> {
+        let updated = sqlx::query!("UPDATE cache SET pinned = ? WHERE rowid = ?", pinned, id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        self.clear_memory();
+        Ok(updated > 0)
+    }
+
+    /// Replace the generated query of an entry. Returns whether the entry exists.
+    pub async fn edit_query(&self, id: i64, generated_query: &str) -> Result<bool, sqlx::Error> {
+        let updated = sqlx::query!(
+            "UPDATE cache SET generated_query = ? WHERE rowid = ?",
+            generated_query,
+            id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        self.clear_memory();
+        Ok(updated > 0)
+    }
+
+    /// Every entry in a portable form, oldest first.
+    pub async fn export(&self) -> Result<Vec<CacheRecord>, sqlx::Error> {
+        let rows = sqlx::query!(
+            r#"SELECT query_prompt, search_engine, language, url, generated_query, routed_engine, options,
+                      llm_model, prompt_version, created_at AS "created_at!: NaiveDateTime", expires_at, pinned
+               FROM cache
+               ORDER BY created_at ASC"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| CacheRecord {
+                query_prompt: row.query_prompt,
+                search_engine: row.search_engine,
+                language: row.language,
+                url: row.url,
+                generated_query: row.generated_query,
+                routed_engine: row.routed_engine,
+                options: decode_options(row.options),
+                llm_model: row.llm_model,
+                prompt_version: row.prompt_version,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                pinned: row.pinned,
+            })
+            .collect())
+    }
+
+    /// Store an exported entry under `search_engine`, its engine normalized with `search::cache_engine_key`. Existing
+    /// entries are only replaced with `overwrite`. The prompt and language are normalized like lookups do, so exports
+    /// of older versions still hit, and expired entries are skipped. Returns whether the entry was written.
+    pub async fn import(&self, record: &CacheRecord, search_engine: &str, overwrite: bool) -> Result<bool, sqlx::Error> {
+        if !record.pinned && record.expires_at.is_some_and(|expires_at| expires_at <= Utc::now().naive_utc()) {
+            return Ok(false);
+        }
+        let query_prompt = normalize_prompt(&record.query_prompt);
+        let language = normalize_prompt(&record.language);
+        let embedding = self.embed(&query_prompt).await.map(|embedding| encode_embedding(&embedding));
+        let options = (!record.options.is_empty())
+            .then(|| serde_json::to_string(&record.options).ok())
+            .flatten();
+        let written = sqlx::query!(
+            "INSERT INTO cache (query_prompt, search_engine, language, url, generated_query, routed_engine, options,
+                                llm_model, prompt_version, created_at, expires_at, pinned, embedding)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(query_prompt, search_engine, language) DO UPDATE SET
+                url = excluded.url,
+                generated_query = excluded.generated_query,
+                routed_engine = excluded.routed_engine,
+                options = excluded.options,
+                llm_model = excluded.llm_model,
+                prompt_version = excluded.prompt_version,
+                created_at = excluded.created_at,
+                expires_at = excluded.expires_at,
+                pinned = excluded.pinned,
+                embedding = excluded.embedding
+             WHERE ?",
+            query_prompt,
+            search_engine,
+            language,
+            record.url,
+            record.generated_query,
+            record.routed_engine,
+            options,
+            record.llm_model,
+            record.prompt_version,
+            record.created_at,
+            record.expires_at,
+            record.pinned,
+            embedding,
+            overwrite
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        self.clear_memory();
+        Ok(written > 0)
+    }
+}
+
+/// Selects cache entries for administration, unset fields match everything.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct CacheFilter {
+    /// SQL `LIKE` pattern on the normalized prompt, e.g. `%vim%`
+    pub prompt_pattern: Option<String>,
+    /// Cache engine, e.g. `google` or `auto:dev`
+    pub engine: Option<String>,
+    /// Only entries created more than this many seconds ago
+    pub older_than_seconds: Option<i64>,
+    /// Whether invalidation also removes pinned entries
+    #[serde(default)]
+    pub include_pinned: bool,
+}
+
+impl CacheFilter {
+    fn created_before(&self) -> Option<NaiveDateTime> {
+        self.older_than_seconds
+            .map(|seconds| Utc::now().naive_utc() - Duration::seconds(seconds))
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct CacheEntry {
+    pub id: i64,
+    pub query_prompt: String,
+    pub search_engine: String,
+    pub language: String,
+    pub url: String,
+    pub generated_query: Option<String>,
+    pub routed_engine: Option<String>,
+    pub llm_model: Option<String>,
+    pub prompt_version: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub hit_count: i64,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub pinned: bool,
+}
+
+/// A cache entry as exported to and imported from JSONL.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct CacheRecord {
+    pub query_prompt: String,
+    /// Cache engine, e.g. `google` or `auto:dev`
+    pub search_engine: String,
+    pub language: String,
+    pub url: String,
+    pub generated_query: Option<String>,
+    pub routed_engine: Option<String>,
+    #[serde(default)]
+    pub options: SearchOptions,
+    pub llm_model: Option<String>,
+    pub prompt_version: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_case_width_and_whitespace() {
+        assert_eq!(normalize_prompt("  How to\tQuit \n VIM  "), "how to quit vim");
+        // NFKC folds full-width forms and ligatures
+        assert_eq!(normalize_prompt("ＲＵＳＴ ﬁle"), "rust file");
+        assert_eq!(normalize_prompt(" \n "), "");
+    }
+
+    #[test]
+    fn cosine_similarity_of_vectors() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_similarity_of_unusable_vectors_is_zero() {
+        assert_eq!(cosine_similarity(&[1.0, 2.0], &[1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    fn policy(ttl_days: i64, engine_ttl: &[(&str, Option<i64>)]) -> CachePolicy {
+        CachePolicy {
+            ttl: Some(Duration::days(ttl_days)),
+            engine_ttl: engine_ttl
+                .iter()
+                .map(|(engine, days)| (engine.to_string(), days.map(Duration::days)))
+                .collect(),
+            max_entries: None,
+            memory_ttl: None,
+        }
+    }
+
+    #[test]
+    fn ttl_follows_the_canonical_or_routed_engine() {
+        let policy = policy(7, &[("duckduckgo", Some(1)), ("github-code", None)]);
+        assert_eq!(policy.ttl_for("ddg", None), Some(Duration::days(1)));
+        assert_eq!(policy.ttl_for("duckduckgo:dev", None), Some(Duration::days(1)));
+        assert_eq!(policy.ttl_for("auto:dev", Some("github")), None);
+        assert_eq!(policy.ttl_for("auto", None), Some(Duration::days(7)));
+    }
+
+    async fn insert_row(pool: &SqlitePool, prompt: &str, engine: &str, routed: Option<&str>, age_days: i64) {
+        let created_at = Utc::now().naive_utc() - Duration::days(age_days);
+        sqlx::query("INSERT INTO cache (query_prompt, search_engine, language, url, routed_engine, created_at)
+                     VALUES (?, ?, 'english', '', ?, ?)")
+            .bind(prompt)
+            .bind(engine)
+            .bind(routed)
+            .bind(created_at)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn memory_tier_rereads_entries_changed_elsewhere() {
+        let pool = crate::service::test_pool().await;
+        let entry = CachedQuery {
+            url: String::new(),
+            generated_query: Some("old".to_string()),
+            routed_engine: None,
+            options: SearchOptions::default(),
+            llm_model: None,
+            prompt_version: None,
+        };
+        let memory_policy = |memory_ttl| CachePolicy {
+            memory_ttl,
+            ..CachePolicy::default()
+        };
+        let rereading = QueryCache::new(pool.clone(), memory_policy(Some(Duration::zero())), 10, None);
+        let keeping = QueryCache::new(pool.clone(), memory_policy(None), 10, None);
+        rereading.insert("rust", "google", "english", &entry, None).await.unwrap();
+        keeping.get("rust", "google", "english").await.unwrap();
+
+        // Edited by another process, e.g. the CLI
+        let cli = QueryCache::new(pool, CachePolicy::default(), 0, None);
+        assert!(cli.edit_query(1, "new").await.unwrap());
+
+        let query = |cached: Option<CachedQuery>| cached.unwrap().generated_query.unwrap();
+        assert_eq!(query(rereading.get("rust", "google", "english").await.unwrap()), "new");
+        assert_eq!(query(keeping.get("rust", "google", "english").await.unwrap()), "old");
+    }
+
+    struct UnusedEmbeddings;
+
+    #[async_trait::async_trait]
+    impl EmbeddingModel for UnusedEmbeddings {
+        async fn embed(&self, _model: &str, _text: &str) -> Result<Vec<f32>, crate::llm::LLMError> {
+            unreachable!("embeddings are passed in")
+        }
+    }
+
+    #[actix_web::test]
+    async fn memory_hits_of_semantic_matches_count_for_the_matched_entry() {
+        let pool = crate::service::test_pool().await;
+        let semantic = SemanticCache {
+            model: Box::new(UnusedEmbeddings),
+            model_id: String::new(),
+            threshold: 0.9,
+            max_candidates: 10,
+        };
+        let cache = QueryCache::new(pool.clone(), CachePolicy::default(), 10, Some(semantic));
+        let entry = CachedQuery {
+            url: String::new(),
+            generated_query: Some("rust".to_string()),
+            routed_engine: None,
+            options: SearchOptions::default(),
+            llm_model: None,
+            prompt_version: None,
+        };
+        cache.insert("rust", "google", "english", &entry, Some(&[1.0, 0.0])).await.unwrap();
+
+        assert!(cache.get_similar("rust lang", "google", "english", &[1.0, 0.1]).await.unwrap().is_some());
+        assert!(cache.get("rust lang", "google", "english").await.unwrap().is_some());
+        cache.flush_hits().await.unwrap();
+
+        let hits: Vec<(String, i64)> = sqlx::query_as("SELECT query_prompt, hit_count FROM cache")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(hits, [("rust".to_string(), 2)]);
+    }
+
+    #[actix_web::test]
+    async fn applies_the_policy_to_entries_cached_without_expiry() {
+        let pool = crate::service::test_pool().await;
+        insert_row(&pool, "old", "google", None, 10).await;
+        insert_row(&pool, "recent", "google", None, 1).await;
+        insert_row(&pool, "routed", "auto:dev", Some("github-code"), 10).await;
+        let cache = QueryCache::new(pool, policy(7, &[("github-code", None)]), 0, None);
+
+        assert_eq!(cache.apply_policy().await.unwrap(), 2);
+        assert_eq!(cache.apply_policy().await.unwrap(), 0);
+        assert!(cache.get("old", "google", "english").await.unwrap().is_none());
+        assert!(cache.get("recent", "google", "english").await.unwrap().is_some());
+        assert!(cache.get("routed", "auto:dev", "english").await.unwrap().is_some());
+        assert_eq!(cache.purge().await.unwrap(), 1);
+    }
+}
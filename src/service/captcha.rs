@@ -0,0 +1,268 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use log::warn;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CaptchaError {
+    #[error("Reqwest error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+}
+
+#[derive(Serialize)]
+struct SiteVerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<String>,
+}
+
+/// The `siteverify` response, Turnstile, hCaptcha and reCAPTCHA share the format.
+#[derive(Deserialize, Debug, Default)]
+pub struct SiteVerifyResponse {
+    pub success: bool,
+    #[serde(rename = "error-codes", default)]
+    pub error_codes: Vec<String>,
+    /// Site the challenge was solved on
+    pub hostname: Option<String>,
+    /// Action the widget was rendered with, Turnstile and reCAPTCHA only
+    pub action: Option<String>,
+    /// reCAPTCHA v3 only, from 0.0 (bot) to 1.0 (human)
+    pub score: Option<f64>,
+}
+
+async fn site_verify(
+    http_client: &Client,
+    url: &str,
+    secret: &str,
+    token: &str,
+    remote_ip: Option<IpAddr>,
+) -> Result<SiteVerifyResponse, CaptchaError> {
+    let request = SiteVerifyRequest {
+        secret,
+        response: token,
+        remoteip: remote_ip.map(|ip| ip.to_string()),
+    };
+    let response = http_client
+        .post(url)
+        .form(&request)
+        .send()
+        .await?
+        .json::<SiteVerifyResponse>()
+        .await?;
+    Ok(response)
+}
+
+/// Checks the token a captcha widget produced.
+#[async_trait]
+pub trait CaptchaVerifier: Send + Sync {
+    /// Name the frontend renders the widget by
+    fn provider(&self) -> &'static str;
+    fn site_key(&self) -> &str;
+    async fn verify(&self, token: &str, remote_ip: Option<IpAddr>) -> Result<SiteVerifyResponse, CaptchaError>;
+}
+
+pub struct Turnstile {
+    site_key: String,
+    secret_key: String,
+    http_client: Client,
+}
+
+impl Turnstile {
+    pub fn new(site_key: String, secret_key: String) -> Self {
+        Self {
+            site_key,
+            secret_key,
+            http_client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for Turnstile {
+    fn provider(&self) -> &'static str {
+        "turnstile"
+    }
+
+    fn site_key(&self) -> &str {
+        &self.site_key
+    }
+
+    async fn verify(&self, token: &str, remote_ip: Option<IpAddr>) -> Result<SiteVerifyResponse, CaptchaError> {
+        site_verify(
+            &self.http_client,
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            &self.secret_key,
+            token,
+            remote_ip,
+        )
+        .await
+    }
+}
+
+pub struct HCaptcha {
+    site_key: String,
+    secret_key: String,
+    http_client: Client,
+}
+
+impl HCaptcha {
+    pub fn new(site_key: String, secret_key: String) -> Self {
+        Self {
+            site_key,
+            secret_key,
+            http_client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for HCaptcha {
+    fn provider(&self) -> &'static str {
+        "hcaptcha"
+    }
+
+    fn site_key(&self) -> &str {
+        &self.site_key
+    }
+
+    async fn verify(&self, token: &str, remote_ip: Option<IpAddr>) -> Result<SiteVerifyResponse, CaptchaError> {
+        site_verify(
+            &self.http_client,
+            "https://api.hcaptcha.com/siteverify",
+            &self.secret_key,
+            token,
+            remote_ip,
+        )
+        .await
+    }
+}
+
+/// reCAPTCHA v3, invisible and scored instead of solved.
+pub struct ReCaptcha {
+    site_key: String,
+    secret_key: String,
+    /// Tokens scoring below this are rejected
+    min_score: f64,
+    http_client: Client,
+}
+
+impl ReCaptcha {
+    pub fn new(site_key: String, secret_key: String, min_score: f64) -> Self {
+        Self {
+            site_key,
+            secret_key,
+            min_score,
+            http_client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl CaptchaVerifier for ReCaptcha {
+    fn provider(&self) -> &'static str {
+        "recaptcha"
+    }
+
+    fn site_key(&self) -> &str {
+        &self.site_key
+    }
+
+    async fn verify(&self, token: &str, remote_ip: Option<IpAddr>) -> Result<SiteVerifyResponse, CaptchaError> {
+        let mut response = site_verify(
+            &self.http_client,
+            "https://www.google.com/recaptcha/api/siteverify",
+            &self.secret_key,
+            token,
+            remote_ip,
+        )
+        .await?;
+        if response.success && response.score.unwrap_or(0.0) < self.min_score {
+            response.success = false;
+            response.error_codes.push(format!("score-too-low ({:?})", response.score));
+        }
+        Ok(response)
+    }
+}
+
+/// Accepts everything, for private instances and development.
+pub struct NoCaptcha;
+
+#[async_trait]
+impl CaptchaVerifier for NoCaptcha {
+    fn provider(&self) -> &'static str {
+        "none"
+    }
+
+    fn site_key(&self) -> &str {
+        ""
+    }
+
+    async fn verify(&self, _token: &str, _remote_ip: Option<IpAddr>) -> Result<SiteVerifyResponse, CaptchaError> {
+        Ok(SiteVerifyResponse {
+            success: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// Verifies captcha tokens and checks they were issued for this site and action.
+pub struct CaptchaService {
+    verifier: Box<dyn CaptchaVerifier>,
+    /// Tokens solved on another site are rejected when set
+    hostname: Option<String>,
+    /// Take the client address from `Forwarded` or `X-Forwarded-For`
+    trust_forwarded_for: bool,
+}
+
+impl CaptchaService {
+    pub fn new(verifier: Box<dyn CaptchaVerifier>, hostname: Option<String>, trust_forwarded_for: bool) -> Self {
+        Self {
+            verifier,
+            hostname,
+            trust_forwarded_for,
+        }
+    }
+
+    pub fn provider(&self) -> &'static str {
+        self.verifier.provider()
+    }
+
+    pub fn site_key(&self) -> &str {
+        self.verifier.site_key()
+    }
+
+    pub fn trust_forwarded_for(&self) -> bool {
+        self.trust_forwarded_for
+    }
+
+    /// Whether the token is valid for `action`, e.g. `login`.
+    pub async fn verify(&self, token: &str, remote_ip: Option<IpAddr>, action: &str) -> Result<bool, CaptchaError> {
+        let provider = self.verifier.provider();
+        if provider == "none" {
+            return Ok(true);
+        }
+        let response = self.verifier.verify(token, remote_ip).await?;
+        if !response.success {
+            warn!("Rejected {} token for {}: {:?}", provider, action, response.error_codes);
+            return Ok(false);
+        }
+        if let Some(hostname) = &self.hostname
+            && response.hostname.as_deref() != Some(hostname.as_str())
+        {
+            warn!("Rejected {} token solved on {:?}", provider, response.hostname);
+            return Ok(false);
+        }
+        // Widgets rendered without an action report an empty one
+        if let Some(response_action) = response.action.as_deref().filter(|action| !action.is_empty())
+            && response_action != action
+        {
+            warn!("Rejected {} token for {} used for {}", provider, response_action, action);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::service::{auth::AuthError, user::Role};
+
+/// Which Discord guild members may sign in, and the in-app role their guild roles grant.
+#[derive(Clone, Debug, Default)]
+pub struct GuildPolicy {
+    /// Members of any of these guilds may sign in
+    pub guild_ids: Vec<String>,
+    /// If set, members also need one of these roles
+    pub required_role_ids: Vec<String>,
+    /// Guild role id to in-app role, the highest matching role wins
+    pub role_mapping: Vec<(String, Role)>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GuildMember {
+    pub roles: Vec<String>,
+}
+
+/// Outcome of a membership check.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GuildAccess {
+    /// With the role mapped from the guild roles, `None` leaves the user's role as it is
+    Granted(Option<Role>),
+    Denied(&'static str),
+}
+
+impl GuildPolicy {
+    pub fn evaluate(&self, members: &[GuildMember]) -> GuildAccess {
+        if members.is_empty() {
+            return GuildAccess::Denied("You need to be a member of the community's Discord server");
+        }
+        let has_role = |role_id: &String| members.iter().any(|member| member.roles.contains(role_id));
+        if !self.required_role_ids.is_empty() && !self.required_role_ids.iter().any(has_role) {
+            return GuildAccess::Denied("You don't have a role on the Discord server that grants access");
+        }
+        let role = self
+            .role_mapping
+            .iter()
+            .filter(|(role_id, _)| has_role(role_id))
+            .map(|(_, role)| *role)
+            .max();
+        GuildAccess::Granted(role)
+    }
+}
+
+/// Gates sign-in on Discord guild membership and re-checks members with a bot token.
+pub struct GuildGate {
+    pool: SqlitePool,
+    policy: GuildPolicy,
+    /// Needed for periodic re-checks, the bot has to be in every configured guild
+    bot_token: Option<String>,
+    /// Admins from `ADMIN_USER_IDS`, never suspended or demoted
+    exempt_user_ids: Vec<i64>,
+    http_client: Client,
+}
+
+impl GuildGate {
+    pub fn new(pool: SqlitePool, policy: GuildPolicy, bot_token: Option<String>, exempt_user_ids: Vec<i64>) -> Self {
+        Self {
+            pool,
+            policy,
+            bot_token,
+            exempt_user_ids,
+            http_client: Client::new(),
+        }
+    }
+
+    pub fn can_recheck(&self) -> bool {
+        self.bot_token.is_some()
+    }
+
+    /// Fetch a member, `None` if the user isn't in the guild. Any other error, e.g. a missing scope or a bot that
+    /// was removed from the guild, fails the check instead of treating the user as a non-member.
+    async fn member(&self, url: String, authorization: String) -> Result<Option<GuildMember>, AuthError> {
+        let response = self
+            .http_client
+            .get(url)
+            .header("Authorization", authorization)
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(Some(response.json().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(AuthError::ProviderApi {
+                provider: "discord".to_string(),
+                message: response.text().await?,
+            }),
+        }
+    }
+
+    /// Check the user behind a login, needs the `guilds.members.read` scope.
+    pub async fn check_user(&self, access_token: &str) -> Result<GuildAccess, AuthError> {
+        let mut members = Vec::new();
+        for guild_id in &self.policy.guild_ids {
+            let url = format!("https://discord.com/api/users/@me/guilds/{guild_id}/member");
+            if let Some(member) = self.member(url, format!("Bearer {access_token}")).await? {
+                members.push(member);
+            }
+        }
+        Ok(self.policy.evaluate(&members))
+    }
+
+    /// Check a Discord user id with the bot token.
+    async fn check_subject(&self, bot_token: &str, subject: &str) -> Result<GuildAccess, AuthError> {
+        let mut members = Vec::new();
+        for guild_id in &self.policy.guild_ids {
+            let url = format!("https://discord.com/api/guilds/{guild_id}/members/{subject}");
+            if let Some(member) = self.member(url, format!("Bot {bot_token}")).await? {
+                members.push(member);
+            }
+        }
+        Ok(self.policy.evaluate(&members))
+    }
+
+    /// Suspend or restore a user and apply the mapped role. A role that came from the mapping is taken back once the
+    /// user no longer has a mapped guild role, roles set by hand are kept.
+    pub async fn apply(&self, user_id: i64, access: &GuildAccess) -> Result<(), sqlx::Error> {
+        if self.exempt_user_ids.contains(&user_id) {
+            return Ok(());
+        }
+        let (suspended, role) = match access {
+            GuildAccess::Granted(role) => (false, role.map(|role| role.as_str())),
+            GuildAccess::Denied(_) => (true, None),
+        };
+        sqlx::query!(
+            "UPDATE users SET
+                suspended = ?1,
+                role = CASE WHEN ?2 IS NOT NULL THEN ?2 WHEN guild_role IS NOT NULL THEN 'user' ELSE role END,
+                guild_role = ?2
+             WHERE id = ?3",
+            suspended,
+            role,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Re-check every user with a Discord identity, returns the number of suspended users.
+    pub async fn recheck(&self) -> Result<u64, AuthError> {
+        let Some(bot_token) = &self.bot_token else {
+            return Ok(0);
+        };
+        let identities = sqlx::query!("SELECT user_id, subject FROM identities WHERE provider = 'discord'")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut suspended = 0;
+        for identity in identities {
+            let access = match self.check_subject(bot_token, &identity.subject).await {
+                Ok(access) => access,
+                Err(e) => {
+                    warn!("Failed to check guild membership of user {}: {}", identity.user_id, e);
+                    continue;
+                }
+            };
+            if matches!(access, GuildAccess::Denied(_)) {
+                suspended += 1;
+            }
+            self.apply(identity.user_id, &access).await?;
+            // Stay well below Discord's rate limits
+            actix_web::rt::time::sleep(Duration::from_millis(250)).await;
+        }
+
+        // Accounts that never linked Discord can't be members
+        let exempt_user_ids = serde_json::to_string(&self.exempt_user_ids).unwrap_or_default();
+        suspended += sqlx::query!(
+            "UPDATE users SET suspended = TRUE
+             WHERE NOT suspended
+               AND id NOT IN (SELECT value FROM json_each(?))
+               AND id NOT IN (SELECT user_id FROM identities WHERE provider = 'discord')",
+            exempt_user_ids
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        info!("Re-checked Discord guild membership, {} users suspended", suspended);
+        Ok(suspended)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(roles: &[&str]) -> GuildMember {
+        GuildMember {
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    fn policy(required_role_ids: &[&str], role_mapping: &[(&str, Role)]) -> GuildPolicy {
+        GuildPolicy {
+            guild_ids: vec!["1".to_string()],
+            required_role_ids: required_role_ids.iter().map(|role| role.to_string()).collect(),
+            role_mapping: role_mapping
+                .iter()
+                .map(|(role_id, role)| (role_id.to_string(), *role))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn denies_non_members() {
+        assert!(matches!(policy(&[], &[]).evaluate(&[]), GuildAccess::Denied(_)));
+    }
+
+    #[test]
+    fn requires_one_of_the_roles() {
+        let policy = policy(&["10", "11"], &[]);
+        assert_eq!(policy.evaluate(&[member(&["11"])]), GuildAccess::Granted(None));
+        assert!(matches!(policy.evaluate(&[member(&["12"])]), GuildAccess::Denied(_)));
+    }
+
+    #[test]
+    fn maps_the_highest_role_across_guilds() {
+        let policy = policy(&[], &[("20", Role::Power), ("21", Role::Admin)]);
+        assert_eq!(policy.evaluate(&[member(&["20"])]), GuildAccess::Granted(Some(Role::Power)));
+        assert_eq!(
+            policy.evaluate(&[member(&["20"]), member(&["21"])]),
+            GuildAccess::Granted(Some(Role::Admin))
+        );
+    }
+
+    async fn gate_with_users(roles: &[Role]) -> GuildGate {
+        let pool = crate::service::test_pool().await;
+        for (index, role) in roles.iter().enumerate() {
+            sqlx::query("INSERT INTO users (id, username, last_request_date, role) VALUES (?, 'user', '2026-10-18', ?)")
+                .bind(index as i64 + 1)
+                .bind(role.as_str())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        GuildGate::new(pool, GuildPolicy::default(), None, vec![1])
+    }
+
+    async fn role_and_suspension(gate: &GuildGate, user_id: i64) -> (String, bool) {
+        sqlx::query_as("SELECT role, suspended FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(&gate.pool)
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn takes_back_mapped_roles() {
+        let gate = gate_with_users(&[Role::Admin, Role::User, Role::User]).await;
+        gate.apply(2, &GuildAccess::Granted(Some(Role::Admin))).await.unwrap();
+        gate.apply(3, &GuildAccess::Granted(Some(Role::Power))).await.unwrap();
+        assert_eq!(role_and_suspension(&gate, 2).await, ("admin".to_string(), false));
+
+        // Left the guild, and lost the mapped guild role
+        gate.apply(2, &GuildAccess::Denied("left")).await.unwrap();
+        gate.apply(3, &GuildAccess::Granted(None)).await.unwrap();
+        assert_eq!(role_and_suspension(&gate, 2).await, ("user".to_string(), true));
+        assert_eq!(role_and_suspension(&gate, 3).await, ("user".to_string(), false));
+    }
+
+    #[actix_web::test]
+    async fn keeps_roles_set_by_hand_and_configured_admins() {
+        let gate = gate_with_users(&[Role::Admin, Role::Power]).await;
+        gate.apply(1, &GuildAccess::Denied("left")).await.unwrap();
+        gate.apply(2, &GuildAccess::Granted(None)).await.unwrap();
+        assert_eq!(role_and_suspension(&gate, 1).await, ("admin".to_string(), false));
+        assert_eq!(role_and_suspension(&gate, 2).await, ("power".to_string(), false));
+    }
+
+    #[test]
+    fn keeps_the_role_without_a_mapped_guild_role() {
+        let policy = policy(&[], &[("20", Role::Power)]);
+        assert_eq!(policy.evaluate(&[member(&["30"])]), GuildAccess::Granted(None));
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use thiserror::Error;
+
+/// Identity provider name of local accounts
+pub const LOCAL_PROVIDER: &str = "local";
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Error, Debug)]
+pub enum LocalAccountError {
+    #[error("Database error")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("Failed to hash password: {0}")]
+    Hash(String),
+    #[error("Wrong username or password")]
+    InvalidCredentials,
+    #[error("Wrong invite code")]
+    InvalidInviteCode,
+    #[error("Usernames have 3 to 32 letters, digits, `.`, `_` or `-`")]
+    InvalidUsername,
+    #[error("Passwords need at least {MIN_PASSWORD_LENGTH} characters")]
+    WeakPassword,
+    #[error("This username is taken")]
+    UsernameTaken,
+    #[error("This account already has a password")]
+    AlreadyRegistered,
+}
+
+async fn hash_password(password: String) -> Result<String, LocalAccountError> {
+    // Hashing is slow on purpose, keep it off the async workers
+    actix_web::rt::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| LocalAccountError::Hash(e.to_string()))
+    })
+    .await
+    .map_err(|e| LocalAccountError::Hash(e.to_string()))?
+}
+
+async fn verify_password(password: String, password_hash: String) -> bool {
+    actix_web::rt::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash)
+            .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Checked against unknown usernames, so they take as long as wrong passwords
+fn dummy_hash() -> String {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH
+        .get_or_init(|| {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(b"not a password", &salt)
+                .map(|hash| hash.to_string())
+                .unwrap_or_default()
+        })
+        .clone()
+}
+
+/// Usernames are matched case-insensitively, the result is the subject of the `local` identity.
+pub fn normalize_username(username: &str) -> Result<String, LocalAccountError> {
+    let username = username.trim();
+    let valid = (3..=32).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !valid {
+        return Err(LocalAccountError::InvalidUsername);
+    }
+    Ok(username.to_ascii_lowercase())
+}
+
+fn check_password(password: &str) -> Result<(), LocalAccountError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(LocalAccountError::WeakPassword);
+    }
+    Ok(())
+}
+
+/// Username and password accounts for instances without an OAuth provider.
+pub struct LocalAccountService {
+    pool: SqlitePool,
+    /// Needed to register when set, otherwise anyone may register
+    invite_code: Option<String>,
+}
+
+impl LocalAccountService {
+    pub fn new(pool: SqlitePool, invite_code: Option<String>) -> Self {
+        Self { pool, invite_code }
+    }
+
+    pub fn requires_invite_code(&self) -> bool {
+        self.invite_code.is_some()
+    }
+
+    fn check_invite_code(&self, invite_code: Option<&str>) -> Result<(), LocalAccountError> {
+        let Some(expected) = &self.invite_code else {
+            return Ok(());
+        };
+        // Digests have the same length, so comparing them doesn't leak the code's length
+        let given = Sha256::digest(invite_code.unwrap_or_default().trim().as_bytes());
+        if given != Sha256::digest(expected.as_bytes()) {
+            return Err(LocalAccountError::InvalidInviteCode);
+        }
+        Ok(())
+    }
+
+    /// Create a user with a password, or add a password to `current_user`. Returns the user id.
+    pub async fn register(
+        &self,
+        username: &str,
+        password: &str,
+        invite_code: Option<&str>,
+        current_user: Option<i64>,
+    ) -> Result<i64, LocalAccountError> {
+        if current_user.is_none() {
+            self.check_invite_code(invite_code)?;
+        }
+        let display_name = username.trim();
+        let username = normalize_username(username)?;
+        check_password(password)?;
+        let password_hash = hash_password(password.to_string()).await?;
+
+        let mut transaction = self.pool.begin().await?;
+        let taken = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM identities WHERE provider = ? AND subject = ?) AS "taken!: bool""#,
+            LOCAL_PROVIDER,
+            username
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        if taken {
+            return Err(LocalAccountError::UsernameTaken);
+        }
+
+        let user_id = match current_user {
+            Some(user_id) => {
+                let registered = sqlx::query_scalar!(
+                    r#"SELECT EXISTS(SELECT 1 FROM local_accounts WHERE user_id = ?) AS "registered!: bool""#,
+                    user_id
+                )
+                .fetch_one(&mut *transaction)
+                .await?;
+                if registered {
+                    return Err(LocalAccountError::AlreadyRegistered);
+                }
+                user_id
+            }
+            None => {
+                let now = chrono::Utc::now().naive_utc();
+                let today = now.date();
+                sqlx::query!(
+                    "INSERT INTO users (username, last_request_date, created_at) VALUES (?, ?, ?)",
+                    display_name,
+                    today,
+                    now
+                )
+                .execute(&mut *transaction)
+                .await?
+                .last_insert_rowid()
+            }
+        };
+
+        sqlx::query!(
+            "INSERT INTO identities (provider, subject, user_id, username) VALUES (?, ?, ?, ?)",
+            LOCAL_PROVIDER,
+            username,
+            user_id,
+            display_name
+        )
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO local_accounts (user_id, username, password_hash) VALUES (?, ?, ?)",
+            user_id,
+            username,
+            password_hash
+        )
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(user_id)
+    }
+
+    /// Returns the user id if the password matches.
+    pub async fn login(&self, username: &str, password: &str) -> Result<i64, LocalAccountError> {
+        let username = username.trim().to_ascii_lowercase();
+        let account = sqlx::query!(
+            r#"SELECT user_id AS "user_id!: i64", password_hash FROM local_accounts WHERE username = ?"#,
+            username
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let (user_id, password_hash) = match account {
+            Some(account) => (Some(account.user_id), account.password_hash),
+            None => (None, dummy_hash()),
+        };
+        let verified = verify_password(password.to_string(), password_hash).await;
+        match user_id {
+            Some(user_id) if verified => Ok(user_id),
+            _ => Err(LocalAccountError::InvalidCredentials),
+        }
+    }
+
+    pub async fn change_password(
+        &self,
+        user_id: i64,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), LocalAccountError> {
+        let password_hash = sqlx::query_scalar!("SELECT password_hash FROM local_accounts WHERE user_id = ?", user_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(LocalAccountError::InvalidCredentials)?;
+        if !verify_password(current_password.to_string(), password_hash).await {
+            return Err(LocalAccountError::InvalidCredentials);
+        }
+        check_password(new_password)?;
+        let password_hash = hash_password(new_password.to_string()).await?;
+        sqlx::query!(
+            "UPDATE local_accounts SET password_hash = ?, updated_at = CURRENT_TIMESTAMP WHERE user_id = ?",
+            password_hash,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use std::net::IpAddr;
+
+use chrono::NaiveDate;
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::service::user::Role;
+
+/// Daily request limits, `None` means unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct QuotaLimits {
+    /// Requests that needed an LLM call
+    pub fresh: Option<i64>,
+    /// Requests served from the query cache
+    pub cached: Option<i64>,
+}
+
+/// How unauthenticated clients are let in.
+#[derive(Clone, Copy, Debug)]
+pub struct GuestPolicy {
+    pub limits: QuotaLimits,
+    /// Clients in the same subnet share a quota
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Guests solve a captcha once per session
+    pub require_captcha: bool,
+    /// Take the client address from `Forwarded` or `X-Forwarded-For`, only safe behind a reverse proxy
+    pub trust_forwarded_for: bool,
+}
+
+impl GuestPolicy {
+    /// Quota key of a client address, e.g. `203.0.113.0/24`.
+    pub fn client_key(&self, ip: IpAddr) -> String {
+        match ip {
+            IpAddr::V4(ip) => {
+                let prefix = self.ipv4_prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                format!("{}/{}", std::net::Ipv4Addr::from(u32::from(ip) & mask), prefix)
+            }
+            IpAddr::V6(ip) => {
+                let prefix = self.ipv6_prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                format!("{}/{}", std::net::Ipv6Addr::from(u128::from(ip) & mask), prefix)
+            }
+        }
+    }
+}
+
+/// Who a request is counted against.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QuotaSubject {
+    User(i64),
+    /// Client key from `GuestPolicy::client_key`
+    Guest(String),
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct QuotaCounter {
+    pub used: i64,
+    pub limit: Option<i64>,
+}
+
+impl QuotaCounter {
+    pub fn is_exhausted(&self) -> bool {
+        self.limit.is_some_and(|limit| self.used >= limit)
+    }
+}
+
+/// What a user has spent today.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct QuotaUsage {
+    pub fresh: QuotaCounter,
+    pub cached: QuotaCounter,
+}
+
+/// Daily accounting of fresh and cached requests per user or guest client, counters reset with the date.
+pub struct QuotaService {
+    pool: SqlitePool,
+    limits: QuotaLimits,
+    /// Limits of users with the power role, admins are unlimited
+    power_limits: QuotaLimits,
+    /// `None` when guests have to sign in
+    guest_policy: Option<GuestPolicy>,
+}
+
+impl QuotaService {
+    pub fn new(
+        pool: SqlitePool,
+        limits: QuotaLimits,
+        power_limits: QuotaLimits,
+        guest_policy: Option<GuestPolicy>,
+    ) -> Self {
+        Self {
+            pool,
+            limits,
+            power_limits,
+            guest_policy,
+        }
+    }
+
+    pub fn guest_policy(&self) -> Option<&GuestPolicy> {
+        self.guest_policy.as_ref()
+    }
+
+    fn today() -> NaiveDate {
+        chrono::Utc::now().date_naive()
+    }
+
+    /// Time until the counters reset at midnight UTC.
+    pub fn until_reset() -> std::time::Duration {
+        let now = chrono::Utc::now().naive_utc();
+        let midnight = (now.date() + chrono::Duration::days(1)).and_hms_opt(0, 0, 0).unwrap_or(now);
+        (midnight - now).to_std().unwrap_or_default()
+    }
+
+    pub async fn usage(&self, subject: &QuotaSubject) -> Result<QuotaUsage, sqlx::Error> {
+        let (counts, limits) = match subject {
+            QuotaSubject::User(user_id) => {
+                let user = sqlx::query!(
+                    "SELECT request_count, cached_request_count, last_request_date, role FROM users WHERE id = ?",
+                    user_id
+                )
+                .fetch_one(&self.pool)
+                .await?;
+                let limits = match user.role.parse().unwrap_or_default() {
+                    Role::User => self.limits,
+                    Role::Power => self.power_limits,
+                    Role::Admin => QuotaLimits {
+                        fresh: None,
+                        cached: None,
+                    },
+                };
+                (
+                    Some((user.request_count, user.cached_request_count, user.last_request_date)),
+                    limits,
+                )
+            }
+            QuotaSubject::Guest(client) => {
+                let guest = sqlx::query!(
+                    "SELECT request_count, cached_request_count, last_request_date FROM guest_quotas WHERE client = ?",
+                    client
+                )
+                .fetch_optional(&self.pool)
+                .await?;
+                let limits = self.guest_policy.map(|policy| policy.limits).unwrap_or(QuotaLimits {
+                    fresh: Some(0),
+                    cached: Some(0),
+                });
+                (
+                    guest.map(|guest| (guest.request_count, guest.cached_request_count, guest.last_request_date)),
+                    limits,
+                )
+            }
+        };
+
+        // Counters from a previous day don't count anymore
+        let (fresh, cached) = match counts {
+            Some((fresh, cached, last_request_date)) if last_request_date == Self::today() => (fresh, cached),
+            _ => (0, 0),
+        };
+        Ok(QuotaUsage {
+            fresh: QuotaCounter {
+                used: fresh,
+                limit: limits.fresh,
+            },
+            cached: QuotaCounter {
+                used: cached,
+                limit: limits.cached,
+            },
+        })
+    }
+
+    /// Count a request against the fresh or the cached counter.
+    pub async fn charge(&self, subject: &QuotaSubject, from_cache: bool) -> Result<(), sqlx::Error> {
+        let today = Self::today();
+        let (fresh, cached) = if from_cache { (0, 1) } else { (1, 0) };
+        match subject {
+            QuotaSubject::User(user_id) => {
+                sqlx::query!(
+                    "UPDATE users SET
+                        request_count = (CASE WHEN last_request_date = ?1 THEN request_count ELSE 0 END) + ?2,
+                        cached_request_count = (CASE WHEN last_request_date = ?1 THEN cached_request_count ELSE 0 END) + ?3,
+                        last_request_date = ?1
+                     WHERE id = ?4",
+                    today,
+                    fresh,
+                    cached,
+                    user_id
+                )
+                .execute(&self.pool)
+                .await?;
+            }
+            QuotaSubject::Guest(client) => {
+                sqlx::query!(
+                    "INSERT INTO guest_quotas (client, request_count, cached_request_count, last_request_date)
+                     VALUES (?4, ?2, ?3, ?1)
+                     ON CONFLICT(client) DO UPDATE SET
+                        request_count = (CASE WHEN last_request_date = ?1 THEN request_count ELSE 0 END) + ?2,
+                        cached_request_count = (CASE WHEN last_request_date = ?1 THEN cached_request_count ELSE 0 END) + ?3,
+                        last_request_date = ?1",
+                    today,
+                    fresh,
+                    cached,
+                    client
+                )
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget guest counters from previous days, returns the number of removed rows.
+    pub async fn purge_guests(&self) -> Result<u64, sqlx::Error> {
+        let today = Self::today();
+        let removed = sqlx::query!("DELETE FROM guest_quotas WHERE last_request_date < ?", today)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(ipv4_prefix: u8, ipv6_prefix: u8) -> GuestPolicy {
+        GuestPolicy {
+            limits: QuotaLimits { fresh: None, cached: None },
+            ipv4_prefix,
+            ipv6_prefix,
+            require_captcha: false,
+            trust_forwarded_for: false,
+        }
+    }
+
+    #[test]
+    fn masks_ipv4_to_prefix() {
+        let ip: IpAddr = "203.0.113.77".parse().unwrap();
+        assert_eq!(policy(24, 64).client_key(ip), "203.0.113.0/24");
+        assert_eq!(policy(32, 64).client_key(ip), "203.0.113.77/32");
+        assert_eq!(policy(0, 64).client_key(ip), "0.0.0.0/0");
+    }
+
+    #[test]
+    fn masks_ipv6_to_prefix() {
+        let ip: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(policy(24, 64).client_key(ip), "2001:db8:1:2::/64");
+        assert_eq!(policy(24, 48).client_key(ip), "2001:db8:1::/48");
+        assert_eq!(policy(24, 128).client_key(ip), "2001:db8:1:2:3:4:5:6/128");
+    }
+
+    #[test]
+    fn clamps_oversized_prefixes() {
+        assert_eq!(policy(40, 64).client_key("203.0.113.77".parse().unwrap()), "203.0.113.77/32");
+        assert_eq!(policy(24, 200).client_key("2001:db8::1".parse().unwrap()), "2001:db8::1/128");
+    }
+}
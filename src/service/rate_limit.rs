@@ -0,0 +1,179 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroU32,
+    time::Duration,
+};
+
+use governor::{
+    clock::{Clock, DefaultClock},
+    state::{keyed::DefaultKeyedStateStore, InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+
+type KeyedLimiter<K> = RateLimiter<K, DefaultKeyedStateStore<K>, DefaultClock>;
+
+/// Requests per minute, `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+    pub per_user: Option<NonZeroU32>,
+    /// IPv6 clients are limited per /64, which a single host usually gets
+    pub per_ip: Option<NonZeroU32>,
+    /// Shared by everyone
+    pub global: Option<NonZeroU32>,
+}
+
+/// Which limit a request ran into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimited {
+    User(Duration),
+    Ip(Duration),
+    Global(Duration),
+}
+
+impl RateLimited {
+    /// How long until the request would be let through.
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            RateLimited::User(wait) | RateLimited::Ip(wait) | RateLimited::Global(wait) => *wait,
+        }
+    }
+}
+
+fn ip_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+    }
+}
+
+/// Per user, per client address and global request rates, kept in memory.
+pub struct RateLimitService {
+    per_user: Option<KeyedLimiter<i64>>,
+    per_ip: Option<KeyedLimiter<IpAddr>>,
+    global: Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
+    clock: DefaultClock,
+    /// Take the client address from `Forwarded` or `X-Forwarded-For`
+    trust_forwarded_for: bool,
+}
+
+impl RateLimitService {
+    pub fn new(limits: RateLimits, trust_forwarded_for: bool) -> Self {
+        Self {
+            per_user: limits.per_user.map(|rate| RateLimiter::keyed(Quota::per_minute(rate))),
+            per_ip: limits.per_ip.map(|rate| RateLimiter::keyed(Quota::per_minute(rate))),
+            global: limits.global.map(|rate| RateLimiter::direct(Quota::per_minute(rate))),
+            clock: DefaultClock::default(),
+            trust_forwarded_for,
+        }
+    }
+
+    pub fn trust_forwarded_for(&self) -> bool {
+        self.trust_forwarded_for
+    }
+
+    /// Count a request. The narrowest limit is checked first, so a client over its own limit doesn't use up the
+    /// global one.
+    pub fn check(&self, user_id: Option<i64>, ip: Option<IpAddr>) -> Result<(), RateLimited> {
+        let now = self.clock.now();
+        if let (Some(limiter), Some(user_id)) = (&self.per_user, user_id) {
+            limiter
+                .check_key(&user_id)
+                .map_err(|not_until| RateLimited::User(not_until.wait_time_from(now)))?;
+        }
+        if let (Some(limiter), Some(ip)) = (&self.per_ip, ip) {
+            limiter
+                .check_key(&ip_key(ip))
+                .map_err(|not_until| RateLimited::Ip(not_until.wait_time_from(now)))?;
+        }
+        if let Some(limiter) = &self.global {
+            limiter
+                .check()
+                .map_err(|not_until| RateLimited::Global(not_until.wait_time_from(now)))?;
+        }
+        Ok(())
+    }
+
+    /// Forget users and addresses whose limit has fully recovered, returns the number of keys left.
+    pub fn purge(&self) -> usize {
+        let mut remaining = 0;
+        if let Some(limiter) = &self.per_user {
+            limiter.retain_recent();
+            limiter.shrink_to_fit();
+            remaining += limiter.len();
+        }
+        if let Some(limiter) = &self.per_ip {
+            limiter.retain_recent();
+            limiter.shrink_to_fit();
+            remaining += limiter.len();
+        }
+        remaining
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(per_user: u32, per_ip: u32, global: u32) -> RateLimits {
+        RateLimits {
+            per_user: NonZeroU32::new(per_user),
+            per_ip: NonZeroU32::new(per_ip),
+            global: NonZeroU32::new(global),
+        }
+    }
+
+    #[test]
+    fn limits_each_user_separately() {
+        let service = RateLimitService::new(limits(2, 0, 0), false);
+        assert_eq!(service.check(Some(1), None), Ok(()));
+        assert_eq!(service.check(Some(1), None), Ok(()));
+        assert!(matches!(service.check(Some(1), None), Err(RateLimited::User(_))));
+        assert_eq!(service.check(Some(2), None), Ok(()));
+        // Guests aren't limited per user
+        assert_eq!(service.check(None, None), Ok(()));
+    }
+
+    #[test]
+    fn limits_ipv6_clients_per_subnet() {
+        let service = RateLimitService::new(limits(0, 1, 0), false);
+        assert_eq!(service.check(None, Some("2001:db8::1".parse().unwrap())), Ok(()));
+        assert!(matches!(
+            service.check(None, Some("2001:db8::2".parse().unwrap())),
+            Err(RateLimited::Ip(_))
+        ));
+        assert_eq!(service.check(None, Some("2001:db8:0:1::1".parse().unwrap())), Ok(()));
+        assert_eq!(service.check(None, Some("203.0.113.1".parse().unwrap())), Ok(()));
+        assert!(matches!(
+            service.check(None, Some("203.0.113.1".parse().unwrap())),
+            Err(RateLimited::Ip(_))
+        ));
+    }
+
+    #[test]
+    fn checks_the_narrowest_limit_first() {
+        let service = RateLimitService::new(limits(1, 0, 2), false);
+        assert_eq!(service.check(Some(1), None), Ok(()));
+        // Rejected by the user limit without using up the global one
+        assert!(matches!(service.check(Some(1), None), Err(RateLimited::User(_))));
+        assert_eq!(service.check(Some(2), None), Ok(()));
+        assert!(matches!(service.check(Some(3), None), Err(RateLimited::Global(_))));
+    }
+
+    #[test]
+    fn reports_when_to_retry() {
+        let service = RateLimitService::new(limits(60, 0, 0), false);
+        let limited = (0..=60).find_map(|_| service.check(Some(1), None).err()).unwrap();
+        // One request is replenished every second
+        assert!(limited.retry_after() > Duration::ZERO);
+        assert!(limited.retry_after() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn unlimited_without_limits() {
+        let service = RateLimitService::new(RateLimits::default(), false);
+        for _ in 0..100 {
+            assert_eq!(service.check(Some(1), Some("203.0.113.1".parse().unwrap())), Ok(()));
+        }
+        assert_eq!(service.purge(), 0);
+    }
+}
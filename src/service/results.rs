@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::model::{results::SearchResult, search::SearchOptions};
+
+#[derive(Error, Debug)]
+pub enum ResultsError {
+    #[error("Reqwest error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("Serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[async_trait]
+pub trait ResultsProvider: Send + Sync {
+    async fn fetch(&self, query: &str, options: &SearchOptions) -> Result<Vec<SearchResult>, ResultsError>;
+    fn name(&self) -> String;
+}
+
+#[derive(Deserialize)]
+struct SearxngResponse {
+    results: Vec<SearxngResult>,
+}
+
+#[derive(Deserialize)]
+struct SearxngResult {
+    url: String,
+    title: String,
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// Fetches results from the JSON API of a SearXNG instance, `json` must be an enabled format there.
+pub struct SearxngResults {
+    base_url: String,
+    http_client: Client,
+}
+
+impl SearxngResults {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http_client: Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ResultsProvider for SearxngResults {
+    async fn fetch(&self, query: &str, options: &SearchOptions) -> Result<Vec<SearchResult>, ResultsError> {
+        let mut params = vec![("q", query.to_string()), ("format", "json".to_string())];
+        if let Some(categories) = &options.categories {
+            params.push(("categories", categories.clone()));
+        }
+        if let Some(locale) = &options.locale {
+            params.push(("language", locale.clone()));
+        }
+        if let Some(freshness) = options.freshness {
+            params.push(("time_range", freshness.as_str().to_string()));
+        }
+
+        let response = self
+            .http_client
+            .get(format!("{}/search", self.base_url))
+            .query(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SearxngResponse>()
+            .await?;
+
+        Ok(response
+            .results
+            .into_iter()
+            .map(|result| SearchResult {
+                title: result.title,
+                url: result.url,
+                snippet: result.content.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    fn name(&self) -> String {
+        "searxng".to_string()
+    }
+}
+
+/// Serves the same results from a local JSON file for every query, useful for development without a provider.
+pub struct FixtureResults {
+    results: Vec<SearchResult>,
+}
+
+impl FixtureResults {
+    pub fn new(fixture_file: &str) -> Result<Self, ResultsError> {
+        let content = std::fs::read_to_string(fixture_file)?;
+        Ok(Self {
+            results: serde_json::from_str(&content)?,
+        })
+    }
+}
+
+#[async_trait]
+impl ResultsProvider for FixtureResults {
+    async fn fetch(&self, _query: &str, _options: &SearchOptions) -> Result<Vec<SearchResult>, ResultsError> {
+        Ok(self.results.clone())
+    }
+
+    fn name(&self) -> String {
+        "fixture".to_string()
+    }
+}
+
+pub struct ResultsService {
+    provider: Box<dyn ResultsProvider>,
+    blocklist: Vec<String>,
+}
+
+impl ResultsService {
+    pub fn new(provider: Box<dyn ResultsProvider>, blocklist: Vec<String>) -> Self {
+        Self {
+            provider,
+            blocklist: blocklist
+                .into_iter()
+                .map(|domain| domain.trim().trim_start_matches("*.").to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Fetch results for `query`, dropping duplicates and results from blocked domains.
+    pub async fn search(&self, query: &str, options: &SearchOptions) -> Result<Vec<SearchResult>, ResultsError> {
+        let results = self.provider.fetch(query, options).await?;
+        let fetched = results.len();
+
+        let mut seen = HashSet::new();
+        let results: Vec<SearchResult> = results
+            .into_iter()
+            .filter(|result| !self.is_blocked(&result.url))
+            .filter(|result| seen.insert(result.url.clone()))
+            .collect();
+
+        log::info!(
+            "Fetched {} results from {} for `{}`, kept {}",
+            fetched,
+            self.provider.name(),
+            query,
+            results.len()
+        );
+        Ok(results)
+    }
+
+    fn is_blocked(&self, url: &str) -> bool {
+        let Some(host) = Url::parse(url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+        else {
+            // Anything we can't link to safely is dropped as well
+            return true;
+        };
+        self.blocklist
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{domain}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_service(blocklist: &[&str]) -> ResultsService {
+        let fixture = FixtureResults::new(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/results.json"))
+            .expect("Failed to load results fixture");
+        ResultsService::new(
+            Box::new(fixture),
+            blocklist.iter().map(|domain| domain.to_string()).collect(),
+        )
+    }
+
+    fn urls(results: &[SearchResult]) -> Vec<&str> {
+        results.iter().map(|result| result.url.as_str()).collect()
+    }
+
+    #[actix_web::test]
+    async fn drops_blocked_domains_and_their_subdomains() {
+        let service = fixture_service(&["*.Spam.example", " "]);
+        let results = service.search("rust", &SearchOptions::default()).await.unwrap();
+        assert_eq!(
+            urls(&results),
+            [
+                "https://doc.rust-lang.org/book/",
+                "https://notspam.example/rust",
+                "https://doc.rust-lang.org/rust-by-example/",
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn keeps_the_first_of_duplicate_urls() {
+        let service = fixture_service(&[]);
+        let results = service.search("rust", &SearchOptions::default()).await.unwrap();
+        let book: Vec<&SearchResult> = results
+            .iter()
+            .filter(|result| result.url == "https://doc.rust-lang.org/book/")
+            .collect();
+        assert_eq!(book.len(), 1);
+        assert_eq!(book[0].title, "The Rust Programming Language");
+        // Only http and https results can be linked
+        assert!(!urls(&results).contains(&"javascript:alert(1)"));
+        assert_eq!(results.len(), 5);
+    }
+}
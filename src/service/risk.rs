@@ -0,0 +1,246 @@
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use lru::LruCache;
+use serde::Serialize;
+
+use crate::service::cache::normalize_prompt;
+
+/// Session entry with the unix time the user last solved a challenge
+pub const CHALLENGE_PASSED_AT: &str = "challenge_passed_at";
+
+/// Clients whose recent requests are remembered, the least recently seen are forgotten first
+const TRACKED_CLIENTS: usize = 10_000;
+/// Requests remembered per client
+const MAX_HISTORY: usize = 256;
+
+/// When a search looks automated.
+#[derive(Clone, Copy, Debug)]
+pub struct RiskPolicy {
+    /// Searches scoring at least this are challenged
+    pub threshold: u32,
+    /// More than `burst_limit` searches within `burst_window` are a burst
+    pub burst_window: Duration,
+    pub burst_limit: usize,
+    /// The same prompt more than `repeat_limit` times within `repeat_window`
+    pub repeat_window: Duration,
+    pub repeat_limit: usize,
+    /// Accounts are new for this long after signing up
+    pub new_account_age: chrono::Duration,
+    /// A solved challenge is trusted for this long
+    pub challenge_ttl: chrono::Duration,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskSignal {
+    Burst,
+    RepeatedPrompt,
+    /// No `User-Agent`, `Accept` or `Accept-Language`, which every browser sends
+    MissingBrowserHeaders,
+    NewAccount,
+}
+
+impl RiskSignal {
+    pub fn weight(&self) -> u32 {
+        match self {
+            RiskSignal::Burst => 40,
+            RiskSignal::RepeatedPrompt => 30,
+            RiskSignal::MissingBrowserHeaders => 30,
+            RiskSignal::NewAccount => 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RiskAssessment {
+    pub score: u32,
+    pub signals: Vec<RiskSignal>,
+}
+
+/// A search of the request being scored.
+pub struct RiskRequest<'a> {
+    /// Who the history is kept for, e.g. `user:42`
+    pub client: &'a str,
+    pub prompt: &'a str,
+    pub browser_headers: bool,
+    /// Signup time of the user, `None` for guests and users that signed up before it was recorded
+    pub account_created_at: Option<NaiveDateTime>,
+}
+
+fn prompt_hash(prompt: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    normalize_prompt(prompt).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Scores searches by how automated they look, from the recent searches of the same client kept in memory.
+pub struct RiskScorer {
+    policy: RiskPolicy,
+    /// Time and prompt hash of recent searches per client, oldest first
+    history: Mutex<LruCache<String, VecDeque<(Instant, u64)>>>,
+}
+
+impl RiskScorer {
+    pub fn new(policy: RiskPolicy) -> Self {
+        Self {
+            policy,
+            history: Mutex::new(LruCache::new(NonZeroUsize::new(TRACKED_CLIENTS).unwrap())),
+        }
+    }
+
+    /// Record a search and score it.
+    pub fn assess(&self, request: &RiskRequest) -> RiskAssessment {
+        let now = Instant::now();
+        let prompt = prompt_hash(request.prompt);
+        let mut signals = Vec::new();
+
+        {
+            let mut history = self.history.lock().unwrap();
+            let searches = history.get_or_insert_mut(request.client.to_string(), VecDeque::new);
+            let retention = self.policy.burst_window.max(self.policy.repeat_window);
+            while searches
+                .front()
+                .is_some_and(|(time, _)| now.duration_since(*time) > retention)
+            {
+                searches.pop_front();
+            }
+            if searches.len() >= MAX_HISTORY {
+                searches.pop_front();
+            }
+            searches.push_back((now, prompt));
+
+            let recent = searches
+                .iter()
+                .filter(|(time, _)| now.duration_since(*time) <= self.policy.burst_window)
+                .count();
+            if recent > self.policy.burst_limit {
+                signals.push(RiskSignal::Burst);
+            }
+            let repeats = searches
+                .iter()
+                .filter(|(time, hash)| *hash == prompt && now.duration_since(*time) <= self.policy.repeat_window)
+                .count();
+            if repeats > self.policy.repeat_limit {
+                signals.push(RiskSignal::RepeatedPrompt);
+            }
+        }
+
+        if !request.browser_headers {
+            signals.push(RiskSignal::MissingBrowserHeaders);
+        }
+        if request
+            .account_created_at
+            .is_some_and(|created_at| Utc::now().naive_utc() - created_at < self.policy.new_account_age)
+        {
+            signals.push(RiskSignal::NewAccount);
+        }
+
+        RiskAssessment {
+            score: signals.iter().map(RiskSignal::weight).sum(),
+            signals,
+        }
+    }
+
+    pub fn is_suspicious(&self, assessment: &RiskAssessment) -> bool {
+        assessment.score >= self.policy.threshold
+    }
+
+    /// Whether a challenge solved at `passed_at`, a unix timestamp, is still trusted.
+    pub fn challenge_valid(&self, passed_at: Option<i64>) -> bool {
+        passed_at.is_some_and(|passed_at| {
+            Utc::now().timestamp() - passed_at < self.policy.challenge_ttl.num_seconds()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scorer() -> RiskScorer {
+        RiskScorer::new(RiskPolicy {
+            threshold: 50,
+            burst_window: Duration::from_secs(60),
+            burst_limit: 3,
+            repeat_window: Duration::from_secs(600),
+            repeat_limit: 1,
+            new_account_age: chrono::Duration::days(1),
+            challenge_ttl: chrono::Duration::hours(1),
+        })
+    }
+
+    fn request<'a>(client: &'a str, prompt: &'a str) -> RiskRequest<'a> {
+        RiskRequest {
+            client,
+            prompt,
+            browser_headers: true,
+            account_created_at: None,
+        }
+    }
+
+    #[test]
+    fn scores_browser_searches_as_safe() {
+        let scorer = scorer();
+        let assessment = scorer.assess(&request("user:1", "rust lifetimes"));
+        assert_eq!(assessment, RiskAssessment { score: 0, signals: vec![] });
+        assert!(!scorer.is_suspicious(&assessment));
+    }
+
+    #[test]
+    fn flags_bursts_per_client() {
+        let scorer = scorer();
+        for prompt in ["a", "b", "c"] {
+            assert!(scorer.assess(&request("user:1", prompt)).signals.is_empty());
+        }
+        assert_eq!(scorer.assess(&request("user:1", "d")).signals, vec![RiskSignal::Burst]);
+        assert!(scorer.assess(&request("user:2", "e")).signals.is_empty());
+    }
+
+    #[test]
+    fn flags_repeated_prompts_after_normalization() {
+        let scorer = scorer();
+        assert!(scorer.assess(&request("user:1", "Rust  Lifetimes")).signals.is_empty());
+        assert_eq!(
+            scorer.assess(&request("user:1", "rust lifetimes")).signals,
+            vec![RiskSignal::RepeatedPrompt]
+        );
+    }
+
+    #[test]
+    fn adds_up_header_and_account_signals() {
+        let scorer = scorer();
+        let assessment = scorer.assess(&RiskRequest {
+            browser_headers: false,
+            account_created_at: Some(Utc::now().naive_utc() - chrono::Duration::hours(1)),
+            ..request("user:1", "rust")
+        });
+        assert_eq!(
+            assessment.signals,
+            vec![RiskSignal::MissingBrowserHeaders, RiskSignal::NewAccount]
+        );
+        assert_eq!(assessment.score, 50);
+        assert!(scorer.is_suspicious(&assessment));
+
+        let established = scorer.assess(&RiskRequest {
+            account_created_at: Some(Utc::now().naive_utc() - chrono::Duration::days(2)),
+            ..request("user:2", "rust")
+        });
+        assert!(established.signals.is_empty());
+    }
+
+    #[test]
+    fn trusts_challenges_until_they_expire() {
+        let scorer = scorer();
+        let now = Utc::now().timestamp();
+        assert!(scorer.challenge_valid(Some(now - 60)));
+        assert!(!scorer.challenge_valid(Some(now - 3600)));
+        assert!(!scorer.challenge_valid(None));
+    }
+}
@@ -1,21 +1,87 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
-use log::info;
-use sqlx::SqlitePool;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 use crate::{
-    llm::{LLMError, LLMPrompt, LargeLanguageModel},
+    llm::{strip_json_fence, LLMError, LLMPrompt, LargeLanguageModel},
     model::{
         search::{
-            Baidu, Bing, Duckduckgo, DuckduckgoHtml, DuckduckgoLite, DuckduckgoNoAi, Google, SearchEngine, Sogou,
+            Baidu, Bing, CratesIo, DocsRs, Duckduckgo, DuckduckgoHtml, DuckduckgoLite, DuckduckgoNoAi,
+            GithubCode, GithubIssues, Google, language_locale, Mdn, SearchEngine, SearchOptions, Searxng, Sogou, StackOverflow,
+            Wikipedia, Youtube,
         },
         GenerateQueryResult, UserQueryRequest,
     },
+    service::{
+        cache::{normalize_prompt, CacheKey, CachedQuery, QueryCache},
+        single_flight::SingleFlight,
+    },
 };
 
 #[derive(serde::Deserialize, Clone, Debug)]
 struct GenerateSearchQueryResponse {
     pub query: String,
+    /// Engine picked by the LLM when routing is enabled
+    pub engine: Option<String>,
+    #[serde(flatten)]
+    pub options: SearchOptions,
+}
+
+/// Engine used for `engine=auto` when the LLM doesn't route to a vertical
+const DEFAULT_ENGINE: &str = "google";
+
+/// Engines `engine=auto` may route to with the `vertical=dev` hint
+const DEV_ENGINES: &[&str] = &["github-code", "github-issues", "stackoverflow", "docs-rs", "crates-io", "mdn"];
+
+/// Whether a vertical hint asks for developer engines.
+pub fn is_dev_vertical(vertical: &str) -> bool {
+    vertical.eq_ignore_ascii_case("dev")
+}
+
+/// Canonical spelling of an engine name, so aliases such as `ddg` and `duckduckgo` share cache entries.
+pub fn canonical_engine(search_engine: &str) -> String {
+    let name = search_engine.trim().to_lowercase();
+    let canonical = match name.as_str() {
+        "ddg" => "duckduckgo",
+        "ddg-lite" => "duckduckgo-lite",
+        "ddg-html" => "duckduckgo-html",
+        "ddg-noai" => "duckduckgo-noai",
+        "github" => "github-code",
+        "so" => "stackoverflow",
+        "docsrs" => "docs-rs",
+        "crates" => "crates-io",
+        "wiki" => "wikipedia",
+        "yt" => "youtube",
+        _ => return name,
+    };
+    canonical.to_string()
+}
+
+/// Engine part of a cache key: the requested engine followed by the vertical, e.g. `auto:dev`.
+fn cache_engine(requested_engine: &str, vertical: Option<&str>) -> String {
+    match vertical {
+        Some(vertical) => format!("{requested_engine}:{}", vertical.trim().to_lowercase()),
+        None => requested_engine.to_string(),
+    }
+}
+
+/// Normalize the engine part of a cache key the way `generate_query` builds it, e.g. `DDG-Lite:Dev` becomes
+/// `duckduckgo:dev`. Url variants of an engine share its queries.
+pub fn cache_engine_key(cache_engine_key: &str, searxng_instances: &[(String, String)]) -> Result<String, SearchError> {
+    let (engine, vertical) = match cache_engine_key.split_once(':') {
+        Some((engine, vertical)) => (engine, Some(vertical)),
+        None => (cache_engine_key, None),
+    };
+    let engine = canonical_engine(engine);
+    let requested_engine = if engine == "auto" {
+        engine
+    } else {
+        engine_instance(&engine, searxng_instances)?.name()
+    };
+    Ok(cache_engine(&requested_engine, vertical))
 }
 
 #[derive(Error, Debug)]
@@ -28,6 +94,10 @@ pub enum SearchError {
     Deserialize(#[from] serde_json::Error),
     #[error("Database error")]
     Sqlx(#[from] sqlx::Error),
+    #[error("Query is not cached and generating it is not allowed")]
+    NotCached,
+    #[error(transparent)]
+    Coalesced(Arc<SearchError>),
 }
 
 #[async_trait]
@@ -37,6 +107,9 @@ pub trait SearchService: Send + Sync {
         query: &str,
         search_engine: &str,
         language: &str,
+        vertical: Option<&str>,
+        options: &SearchOptions,
+        allow_generation: bool,
     ) -> Result<GenerateQueryResult, SearchError>;
 }
 
@@ -44,7 +117,12 @@ pub struct SearchServiceImpl {
     llm: Box<dyn LargeLanguageModel>,
     llm_model: String,
     prompt_template: String,
-    pool: SqlitePool,
+    /// Short hash of `prompt_template`, stored with cached queries
+    prompt_version: String,
+    cache: Arc<QueryCache>,
+    /// Generations in progress, with whether the entry was generated rather than found in the cache
+    in_flight: SingleFlight<CacheKey, (CachedQuery, bool), SearchError>,
+    searxng_instances: Vec<(String, String)>,
 }
 
 impl SearchServiceImpl {
@@ -52,64 +130,104 @@ impl SearchServiceImpl {
         llm: Box<dyn LargeLanguageModel>,
         llm_model: String,
         prompt_file: String,
-        pool: SqlitePool,
+        cache: Arc<QueryCache>,
+        searxng_instances: Vec<(String, String)>,
     ) -> Self {
         let prompt_template = std::fs::read_to_string(prompt_file).expect("Failed to read prompt file");
+        let prompt_version = hex::encode(&Sha256::digest(prompt_template.as_bytes())[..6]);
         Self {
             llm,
             llm_model,
             prompt_template,
-            pool,
+            prompt_version,
+            cache,
+            in_flight: SingleFlight::default(),
+            searxng_instances,
         }
     }
-}
 
-#[async_trait]
-impl SearchService for SearchServiceImpl {
-    async fn generate_query(
+    /// Build the url for a generated query, on the routed engine if it's known.
+    fn build_result(
         &self,
-        query_prompt: &str,
-        search_engine: &str,
-        language: &str,
-    ) -> Result<GenerateQueryResult, SearchError> {
-        let cached_result = sqlx::query!(
-            "SELECT url FROM cache WHERE query_prompt = ? AND search_engine = ? AND language = ?",
-            query_prompt,
-            search_engine,
-            language
-        )
-        .fetch_optional(&self.pool)
-        .await?;
+        query: String,
+        routed_engine: Option<&str>,
+        fallback_instance: &dyn SearchEngine,
+        options: SearchOptions,
+        from_cache: bool,
+    ) -> GenerateQueryResult {
+        let routed_instance = routed_engine.and_then(|routed| match self.search_engine(routed) {
+            Ok(instance) => Some(instance),
+            Err(_) => {
+                warn!(
+                    "LLM routed to unknown engine `{}`, falling back to {}",
+                    routed,
+                    fallback_instance.name()
+                );
+                None
+            }
+        });
+        let search_engine_instance = routed_instance.as_deref().unwrap_or(fallback_instance);
 
-        if let Some(record) = cached_result {
-            info!("Cache hit for query: {}", query_prompt);
-            return Ok(GenerateQueryResult { url: record.url });
+        let encoded_query = urlencoding::encode(&query);
+        let url = search_engine_instance.generate_url(&encoded_query, &options);
+        GenerateQueryResult {
+            url,
+            query: Some(query),
+            options,
+            from_cache,
         }
+    }
 
-        let search_engine_instance: Box<dyn SearchEngine> =
-            match search_engine.to_lowercase().as_str() {
-                "ddg" | "duckduckgo" => Box::new(Duckduckgo::default()),
-                "ddg-lite" | "duckduckgo-lite" => Box::new(DuckduckgoLite::default()),
-                "ddg-html" | "duckduckgo-html" => Box::new(DuckduckgoHtml::default()),
-                "ddg-noai" | "duckduckgo-noai" => Box::new(DuckduckgoNoAi::default()),
-                "google" => Box::new(Google::default()),
-                "baidu" => Box::new(Baidu::default()),
-                "bing" => Box::new(Bing::default()),
-                "sogou" => Box::new(Sogou::default()),
-                _ => return Err(SearchError::UnknownEngine(search_engine.to_string())),
-            };
+    /// Result of a cached or generated entry. Options from the request win over what the LLM detected, which wins
+    /// over `fallback`.
+    fn entry_result(
+        &self,
+        entry: CachedQuery,
+        fallback_instance: &dyn SearchEngine,
+        options: SearchOptions,
+        fallback: SearchOptions,
+        from_cache: bool,
+    ) -> GenerateQueryResult {
+        match entry.generated_query {
+            Some(generated_query) => self.build_result(
+                generated_query,
+                entry.routed_engine.as_deref(),
+                fallback_instance,
+                options.or(entry.options).or(fallback),
+                from_cache,
+            ),
+            // Entries cached before the raw query was stored only have their url
+            None => GenerateQueryResult {
+                url: entry.url,
+                query: None,
+                options: options.or(fallback),
+                from_cache,
+            },
+        }
+    }
 
+    /// Ask the LLM for a query, the returned entry has no url yet.
+    async fn generate(
+        &self,
+        query_prompt: &str,
+        requested_engine: String,
+        language: &str,
+        vertical: Option<&str>,
+        routing: Option<&[&str]>,
+    ) -> Result<CachedQuery, SearchError> {
         info!(
             "Generate query using engine {} with prompt `{}` and language `{}`",
-            search_engine_instance.name(),
-            query_prompt,
-            language
+            requested_engine, query_prompt, language
         );
 
         let user_query_request = UserQueryRequest {
-            engine: search_engine_instance.name(),
+            engine: requested_engine,
             prompt: query_prompt.to_string(),
             language: language.to_string(),
+            vertical: vertical.map(|vertical| vertical.to_string()),
+            engines: routing
+                .filter(|engines| !engines.is_empty())
+                .map(|engines| engines.iter().map(|engine| engine.to_string()).collect()),
         };
 
         let contents = vec![
@@ -126,40 +244,297 @@ impl SearchService for SearchServiceImpl {
 
         let ai_response = self.llm.query(&self.llm_model, &contents).await;
 
-        let mut content = match ai_response {
+        let content = match ai_response {
             Ok(content) => content,
             Err(err) => return Err(SearchError::from(err)),
         };
 
-        if content.starts_with("```json") {
-            content = content
-                .trim_start_matches("```json")
-                .trim_end_matches("```")
-                .to_string();
-        }
-
-        let response: GenerateSearchQueryResponse = serde_json::from_str(&content)?;
-
-        let encoded_query = urlencoding::encode(&response.query);
-        let url = search_engine_instance.generate_url(&encoded_query.to_string());
+        let response: GenerateSearchQueryResponse = serde_json::from_str(strip_json_fence(&content))?;
+        // Only keep a route to an engine the LLM was offered
+        let routed_engine = response
+            .engine
+            .map(|engine| canonical_engine(&engine))
+            .filter(|engine| routing.is_some_and(|engines| engines.is_empty() || engines.contains(&engine.as_str())));
 
         info!(
             "Query successful generated for {} with prompt `{}` -> `{}`",
-            search_engine_instance.name(),
+            routed_engine.as_deref().unwrap_or(&user_query_request.engine),
             query_prompt,
             &response.query
         );
 
-        sqlx::query!(
-            "INSERT INTO cache (query_prompt, search_engine, language, url) VALUES (?, ?, ?, ?)",
-            query_prompt,
-            search_engine,
-            language,
-            url
+        Ok(CachedQuery {
+            url: String::new(),
+            generated_query: Some(response.query),
+            routed_engine,
+            options: response.options,
+            llm_model: Some(self.llm_model.clone()),
+            prompt_version: Some(self.prompt_version.clone()),
+        })
+    }
+
+    fn search_engine(&self, search_engine: &str) -> Result<Box<dyn SearchEngine>, SearchError> {
+        engine_instance(search_engine, &self.searxng_instances)
+    }
+}
+
+/// Engine for a name or alias, SearXNG engines need a configured instance.
+fn engine_instance(
+    search_engine: &str,
+    searxng_instances: &[(String, String)],
+) -> Result<Box<dyn SearchEngine>, SearchError> {
+    let name = canonical_engine(search_engine);
+    let engine: Box<dyn SearchEngine> = match name.as_str() {
+        "duckduckgo" => Box::new(Duckduckgo::default()),
+        "duckduckgo-lite" => Box::new(DuckduckgoLite::default()),
+        "duckduckgo-html" => Box::new(DuckduckgoHtml::default()),
+        "duckduckgo-noai" => Box::new(DuckduckgoNoAi::default()),
+        "google" => Box::new(Google::default()),
+        "baidu" => Box::new(Baidu::default()),
+        "bing" => Box::new(Bing::default()),
+        "sogou" => Box::new(Sogou::default()),
+        "github-code" => Box::new(GithubCode::default()),
+        "github-issues" => Box::new(GithubIssues::default()),
+        "stackoverflow" => Box::new(StackOverflow::default()),
+        "docs-rs" => Box::new(DocsRs::default()),
+        "crates-io" => Box::new(CratesIo::default()),
+        "mdn" => Box::new(Mdn::default()),
+        "wikipedia" => Box::new(Wikipedia::default()),
+        "youtube" => Box::new(Youtube::default()),
+        "searxng" => match searxng_instances.first() {
+            Some((_, base_url)) => Box::new(Searxng::new(base_url)),
+            None => return Err(SearchError::UnknownEngine(search_engine.to_string())),
+        },
+        _ if name.starts_with("wikipedia-") => Box::new(Wikipedia::new(name.strip_prefix("wikipedia-"))),
+        _ => {
+            // Named instances are addressed as `searxng-<name>`
+            let instance = name.strip_prefix("searxng-").and_then(|instance_name| {
+                searxng_instances
+                    .iter()
+                    .find(|(configured, _)| configured == instance_name)
+            });
+            match instance {
+                Some((_, base_url)) => Box::new(Searxng::new(base_url)),
+                None => return Err(SearchError::UnknownEngine(search_engine.to_string())),
+            }
+        }
+    };
+    Ok(engine)
+}
+
+#[async_trait]
+impl SearchService for SearchServiceImpl {
+    async fn generate_query(
+        &self,
+        query_prompt: &str,
+        search_engine: &str,
+        language: &str,
+        vertical: Option<&str>,
+        options: &SearchOptions,
+        allow_generation: bool,
+    ) -> Result<GenerateQueryResult, SearchError> {
+        let search_engine = canonical_engine(search_engine);
+        let search_engine = search_engine.as_str();
+
+        // For `engine=auto` the LLM picks the engine, among the developer engines with the `vertical=dev` hint.
+        // An empty list allows every engine.
+        let auto_engine = search_engine == "auto";
+        let routing: Option<&[&str]> = auto_engine.then(|| {
+            if vertical.is_some_and(is_dev_vertical) {
+                DEV_ENGINES
+            } else {
+                &[]
+            }
+        });
+        let fallback_engine = if auto_engine { DEFAULT_ENGINE } else { search_engine };
+
+        let fallback_instance = self.search_engine(fallback_engine)?;
+        // Queries are cached per engine dialect the LLM writes for, so url variants share them
+        let requested_engine = if auto_engine {
+            "auto".to_string()
+        } else {
+            fallback_instance.name()
+        };
+
+        // The requested language is the last resort for the locale
+        let language_options = SearchOptions {
+            locale: language_locale(language),
+            ..SearchOptions::default()
+        };
+
+        let prompt_key = normalize_prompt(query_prompt);
+        let language_key = normalize_prompt(language);
+        let cache_engine = cache_engine(&requested_engine, vertical);
+
+        let mut cached_result = self.cache.get(&prompt_key, &cache_engine, &language_key).await?;
+
+        // Fall back to a similar prompt when semantic matching is enabled
+        let mut embedding = None;
+        if cached_result.is_none() {
+            embedding = self.cache.embed(&prompt_key).await;
+            if let Some(embedding) = &embedding {
+                cached_result = self
+                    .cache
+                    .get_similar(&prompt_key, &cache_engine, &language_key, embedding)
+                    .await?;
+            }
+        }
+
+        if let Some(cached) = cached_result {
+            info!("Cache hit for query: {}", query_prompt);
+            return Ok(self.entry_result(cached, fallback_instance.as_ref(), options.clone(), language_options, true));
+        }
+
+        if !allow_generation {
+            return Err(SearchError::NotCached);
+        }
+
+        // Concurrent misses for the same key wait for a single LLM call
+        let flight_key = (prompt_key.clone(), cache_engine.clone(), language_key.clone());
+        let ((entry, generated), led) = self
+            .in_flight
+            .run(flight_key, || async {
+                // A flight for this key may have finished since the lookup above
+                if let Some(cached) = self.cache.get(&prompt_key, &cache_engine, &language_key).await? {
+                    return Ok((cached, false));
+                }
+                let mut entry = self
+                    .generate(query_prompt, requested_engine, language, vertical, routing)
+                    .await?;
+                // Url at generation time, only kept for reference
+                entry.url = self
+                    .build_result(
+                        entry.generated_query.clone().unwrap_or_default(),
+                        entry.routed_engine.as_deref(),
+                        fallback_instance.as_ref(),
+                        entry.options.clone(),
+                        false,
+                    )
+                    .url;
+                self.cache
+                    .insert(
+                        &prompt_key,
+                        &cache_engine,
+                        &language_key,
+                        &entry,
+                        embedding.as_deref(),
+                    )
+                    .await?;
+                Ok((entry, true))
+            })
+            .await
+            .map_err(|e| Arc::try_unwrap(e).unwrap_or_else(SearchError::Coalesced))?;
+
+        // Only the caller that made the LLM call pays for a fresh generation, the others got a cached query
+        let from_cache = !(led && generated);
+        Ok(self.entry_result(entry, fallback_instance.as_ref(), options.clone(), language_options, from_cache))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::{cache::CachePolicy, test_pool};
+
+    struct UnreachableLlm;
+
+    #[async_trait]
+    impl LargeLanguageModel for UnreachableLlm {
+        async fn query(&self, _model: &str, _contents: &[LLMPrompt]) -> Result<String, LLMError> {
+            panic!("cached queries don't need the LLM")
+        }
+    }
+
+    fn service(cache: Arc<QueryCache>, searxng_instances: Vec<(String, String)>) -> SearchServiceImpl {
+        SearchServiceImpl::new(
+            Box::new(UnreachableLlm),
+            "test".to_string(),
+            "prompt.md".to_string(),
+            cache,
+            searxng_instances,
         )
-        .execute(&self.pool)
-        .await?;
+    }
+
+    async fn service_with(engine: &str, entry: CachedQuery) -> SearchServiceImpl {
+        let cache = Arc::new(QueryCache::new(test_pool().await, CachePolicy::default(), 0, None));
+        cache
+            .insert("mozart", engine, "english", &entry, None)
+            .await
+            .unwrap();
+        service(cache, Vec::new())
+    }
 
-        Ok(GenerateQueryResult { url })
+    fn entry(locale: &str) -> CachedQuery {
+        CachedQuery {
+            url: String::new(),
+            generated_query: Some("mozart".to_string()),
+            routed_engine: None,
+            options: SearchOptions {
+                locale: Some(locale.to_string()),
+                ..SearchOptions::default()
+            },
+            llm_model: None,
+            prompt_version: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn detected_locale_wins_over_the_requested_language() {
+        let service = service_with("wikipedia", entry("de")).await;
+        let result = service
+            .generate_query("Mozart", "wikipedia", "English", None, &SearchOptions::default(), false)
+            .await
+            .unwrap();
+        assert!(result.from_cache);
+        assert_eq!(result.options.locale.as_deref(), Some("de"));
+        assert!(result.url.starts_with("https://de.wikipedia.org/"));
+    }
+
+    #[test]
+    fn cache_engine_keys_match_lookups() {
+        let searxng = vec![("home".to_string(), "http://127.0.0.1:8888".to_string())];
+        let key = |engine: &str| cache_engine_key(engine, &searxng).unwrap();
+        assert_eq!(key("DDG-Lite"), "duckduckgo");
+        assert_eq!(key("wikipedia-de"), "wikipedia");
+        assert_eq!(key("searxng-home:Dev"), "searxng:dev");
+        assert_eq!(key("auto:dev"), "auto:dev");
+        assert!(cache_engine_key("searxng-other", &searxng).is_err());
+    }
+
+    #[actix_web::test]
+    async fn exported_entries_are_found_after_import() {
+        let exporting = service_with("duckduckgo:dev", entry("de")).await;
+        let mut records = exporting.cache.export().await.unwrap();
+        // Exports of older versions keyed entries by the engine as requested
+        records[0].search_engine = "DDG-Lite:Dev".to_string();
+        records[0].query_prompt = "  Mozart ".to_string();
+
+        let cache = Arc::new(QueryCache::new(test_pool().await, CachePolicy::default(), 0, None));
+        for record in &records {
+            let search_engine = cache_engine_key(&record.search_engine, &[]).unwrap();
+            assert!(cache.import(record, &search_engine, false).await.unwrap());
+        }
+        let importing = service(cache, Vec::new());
+        for engine in ["duckduckgo", "ddg-html"] {
+            let result = importing
+                .generate_query("mozart", engine, "English", Some("dev"), &SearchOptions::default(), false)
+                .await
+                .unwrap();
+            assert_eq!(result.query.as_deref(), Some("mozart"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn requested_locale_wins_over_the_detected_one() {
+        let service = service_with("wikipedia", entry("de")).await;
+        let options = SearchOptions {
+            locale: Some("fr".to_string()),
+            ..SearchOptions::default()
+        };
+        let result = service
+            .generate_query("Mozart", "wikipedia", "English", None, &options, false)
+            .await
+            .unwrap();
+        assert_eq!(result.options.locale.as_deref(), Some("fr"));
     }
 }
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use actix_session::{
+    storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+    Session, SessionInsertError,
+};
+use actix_web::cookie::time::Duration as CookieDuration;
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+
+/// Session entries the store also keeps in their own columns
+const USER_ID: &str = "user_id";
+const SESSION_ID: &str = "session_id";
+const USER_AGENT: &str = "user_agent";
+
+/// Activity is recorded at most this often, so not every request writes to the database
+const LAST_SEEN_GRANULARITY_SECONDS: i64 = 60;
+
+/// An active session as shown to its owner.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    /// The session making the request
+    pub current: bool,
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Read a JSON encoded session entry.
+fn entry<T: serde::de::DeserializeOwned>(state: &HashMap<String, String>, key: &str) -> Option<T> {
+    state.get(key).and_then(|value| serde_json::from_str(value).ok())
+}
+
+/// Mark a session as signed in, with a fresh key so a key known before the login is useless.
+pub fn sign_in(session: &Session, user_id: i64, user_agent: Option<&str>) -> Result<(), SessionInsertError> {
+    session.renew();
+    session.insert(USER_ID, user_id)?;
+    session.insert(SESSION_ID, random_string(16))?;
+    session.insert(USER_AGENT, user_agent)?;
+    Ok(())
+}
+
+/// Id of a signed in session, as listed by `SqliteSessionStore::list`.
+pub fn session_id(session: &Session) -> Option<String> {
+    session.get::<String>(SESSION_ID).unwrap_or(None)
+}
+
+/// Sessions kept in SQLite, so they can be listed and revoked. The cookie only holds a random key.
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+    pool: SqlitePool,
+    /// Sessions end this long after they were created
+    max_age: Duration,
+    /// Sessions end when unused for this long, `None` to only apply `max_age`
+    idle_timeout: Option<Duration>,
+}
+
+impl SqliteSessionStore {
+    pub fn new(pool: SqlitePool, max_age: Duration, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            pool,
+            max_age,
+            idle_timeout,
+        }
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    fn idle_expires_at(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.idle_timeout.map(|idle_timeout| now + idle_timeout)
+    }
+
+    async fn insert(&self, state: &HashMap<String, String>) -> Result<SessionKey, anyhow::Error> {
+        let key = random_string(64);
+        let key_hash = hash_key(&key);
+        let now = Utc::now().naive_utc();
+        let max_expires_at = now + self.max_age;
+        let expires_at = self
+            .idle_expires_at(now)
+            .map_or(max_expires_at, |idle_expires_at| idle_expires_at.min(max_expires_at));
+        let session_id = entry::<String>(state, SESSION_ID);
+        let user_id = entry::<i64>(state, USER_ID);
+        let user_agent = entry::<String>(state, USER_AGENT);
+        let state = serde_json::to_string(state)?;
+        sqlx::query!(
+            "INSERT INTO sessions
+                (key_hash, session_id, user_id, user_agent, state, created_at, last_seen_at, max_expires_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            key_hash,
+            session_id,
+            user_id,
+            user_agent,
+            state,
+            now,
+            now,
+            max_expires_at,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(SessionKey::try_from(key)?)
+    }
+
+    /// Active sessions of a user, most recently used first.
+    pub async fn list(&self, user_id: i64, current: Option<&str>) -> Result<Vec<SessionInfo>, sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        let sessions = sqlx::query!(
+            r#"SELECT session_id AS "session_id!", user_agent, created_at, last_seen_at FROM sessions
+               WHERE user_id = ? AND session_id IS NOT NULL AND expires_at > ?
+               ORDER BY last_seen_at DESC"#,
+            user_id,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: current == Some(session.session_id.as_str()),
+                id: session.session_id,
+                user_agent: session.user_agent,
+                created_at: session.created_at,
+                last_seen_at: session.last_seen_at,
+            })
+            .collect())
+    }
+
+    /// Returns whether the user had such a session.
+    pub async fn revoke(&self, user_id: i64, session_id: &str) -> Result<bool, sqlx::Error> {
+        let removed = sqlx::query!(
+            "DELETE FROM sessions WHERE user_id = ? AND session_id = ?",
+            user_id,
+            session_id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(removed > 0)
+    }
+
+    /// Sign a user out everywhere, returns the number of ended sessions.
+    pub async fn revoke_all(&self, user_id: i64) -> Result<u64, sqlx::Error> {
+        let removed = sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(removed)
+    }
+
+    /// Remove expired sessions, returns the number of removed rows.
+    pub async fn purge(&self) -> Result<u64, sqlx::Error> {
+        let now = Utc::now().naive_utc();
+        let removed = sqlx::query!("DELETE FROM sessions WHERE expires_at <= ?", now)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(removed)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SqliteSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<HashMap<String, String>>, LoadError> {
+        let key_hash = hash_key(session_key.as_ref());
+        let now = Utc::now().naive_utc();
+        let state = sqlx::query_scalar!(
+            "SELECT state FROM sessions WHERE key_hash = ? AND expires_at > ?",
+            key_hash,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| LoadError::Other(e.into()))?;
+        state
+            .map(|state| serde_json::from_str(&state))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        _ttl: &CookieDuration,
+    ) -> Result<SessionKey, SaveError> {
+        self.insert(&session_state).await.map_err(SaveError::Other)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        _ttl: &CookieDuration,
+    ) -> Result<SessionKey, UpdateError> {
+        let key_hash = hash_key(session_key.as_ref());
+        let now = Utc::now().naive_utc();
+        let idle_expires_at = self.idle_expires_at(now);
+        let session_id = entry::<String>(&session_state, SESSION_ID);
+        let user_id = entry::<i64>(&session_state, USER_ID);
+        let user_agent = entry::<String>(&session_state, USER_AGENT);
+        let state = serde_json::to_string(&session_state).map_err(|e| UpdateError::Serialization(e.into()))?;
+        let updated = sqlx::query!(
+            "UPDATE sessions SET
+                session_id = ?, user_id = ?, user_agent = ?, state = ?, last_seen_at = ?,
+                expires_at = MIN(max_expires_at, COALESCE(?, max_expires_at))
+             WHERE key_hash = ? AND expires_at > ?",
+            session_id,
+            user_id,
+            user_agent,
+            state,
+            now,
+            idle_expires_at,
+            key_hash,
+            now
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| UpdateError::Other(e.into()))?
+        .rows_affected();
+
+        // The session ended in the meantime, e.g. it expired or was revoked. Start a new one, signed out so a revoked
+        // login isn't brought back
+        if updated == 0 {
+            let mut session_state = session_state;
+            for key in [USER_ID, SESSION_ID, USER_AGENT] {
+                session_state.remove(key);
+            }
+            return self.insert(&session_state).await.map_err(UpdateError::Other);
+        }
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, _ttl: &CookieDuration) -> Result<(), anyhow::Error> {
+        let key_hash = hash_key(session_key.as_ref());
+        let now = Utc::now().naive_utc();
+        let idle_expires_at = self.idle_expires_at(now);
+        let seen_before = now - Duration::seconds(LAST_SEEN_GRANULARITY_SECONDS);
+        sqlx::query!(
+            "UPDATE sessions SET last_seen_at = ?, expires_at = MIN(max_expires_at, COALESCE(?, max_expires_at))
+             WHERE key_hash = ? AND expires_at > ? AND last_seen_at < ?",
+            now,
+            idle_expires_at,
+            key_hash,
+            now,
+            seen_before
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let key_hash = hash_key(session_key.as_ref());
+        sqlx::query!("DELETE FROM sessions WHERE key_hash = ?", key_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+type Outcome<T, E> = Option<Result<T, Arc<E>>>;
+
+/// Deduplicates concurrent work by key: the first caller runs it, callers arriving meanwhile wait for
+/// and share its outcome.
+pub struct SingleFlight<K, T, E> {
+    flights: Mutex<HashMap<K, watch::Sender<Outcome<T, E>>>>,
+}
+
+/// Removes the flight when the leader finishes or is cancelled, waiting callers see the channel close.
+struct FlightGuard<'a, K: Eq + Hash, T, E> {
+    flights: &'a Mutex<HashMap<K, watch::Sender<Outcome<T, E>>>>,
+    key: K,
+}
+
+impl<K: Eq + Hash, T, E> Drop for FlightGuard<'_, K, T, E> {
+    fn drop(&mut self) {
+        self.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl<K, T, E> Default for SingleFlight<K, T, E> {
+    fn default() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, T: Clone, E> SingleFlight<K, T, E> {
+    /// Run `work` unless a flight for `key` is running already, then share its outcome.
+    /// Returns the value and whether this caller ran `work` itself.
+    pub async fn run<F, Fut>(&self, key: K, work: F) -> Result<(T, bool), Arc<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        loop {
+            let mut receiver = {
+                let mut flights = self.flights.lock().unwrap();
+                match flights.get(&key) {
+                    Some(sender) => sender.subscribe(),
+                    None => {
+                        flights.insert(key.clone(), watch::channel(None).0);
+                        break;
+                    }
+                }
+            };
+            if let Ok(outcome) = receiver.wait_for(Option::is_some).await {
+                return outcome.clone().expect("outcome is set").map(|value| (value, false));
+            }
+            // The leader was cancelled before it finished, take over
+        }
+
+        let guard = FlightGuard {
+            flights: &self.flights,
+            key,
+        };
+        let outcome = work().await.map_err(Arc::new);
+        if let Some(sender) = self.flights.lock().unwrap().get(&guard.key) {
+            sender.send_replace(Some(outcome.clone()));
+        }
+        drop(guard);
+        outcome.map(|value| (value, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::{join, poll};
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn waiting_callers_share_the_result() {
+        let flight = SingleFlight::<u32, String, ()>::default();
+        let runs = AtomicUsize::new(0);
+        let release = Notify::new();
+        let work = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            release.notified().await;
+            Ok("query".to_string())
+        };
+
+        let (leader, follower, _) = join!(flight.run(1, work), flight.run(1, work), async { release.notify_one() });
+        assert_eq!(leader, Ok(("query".to_string(), true)));
+        assert_eq!(follower, Ok(("query".to_string(), false)));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(flight.flights.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn waiting_callers_share_the_error() {
+        let flight = SingleFlight::<u32, String, &str>::default();
+        let runs = AtomicUsize::new(0);
+        let release = Notify::new();
+        let work = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            release.notified().await;
+            Err("llm failed")
+        };
+
+        let (leader, follower, _) = join!(flight.run(1, work), flight.run(1, work), async { release.notify_one() });
+        assert_eq!(*leader.unwrap_err(), "llm failed");
+        assert_eq!(*follower.unwrap_err(), "llm failed");
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn a_waiting_caller_takes_over_from_a_cancelled_leader() {
+        let flight = SingleFlight::<u32, u32, ()>::default();
+        let mut leader = Box::pin(flight.run(1, std::future::pending::<Result<u32, ()>>));
+        assert!(poll!(&mut leader).is_pending());
+
+        let (follower, _) = join!(flight.run(1, || async { Ok(2) }), async move { drop(leader) });
+        assert_eq!(follower, Ok((2, true)));
+        assert!(flight.flights.lock().unwrap().is_empty());
+    }
+}
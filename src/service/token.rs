@@ -0,0 +1,198 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TokenError {
+    #[error("Database error")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("Invalid or expired token")]
+    Invalid,
+    #[error("Token lacks the `{0}` scope")]
+    MissingScope(&'static str),
+}
+
+/// What a personal access token may be used for.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Generate queries with `/search/ai`
+    Search,
+    /// Read the account and its quota at `/auth/me`
+    Account,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Search => "search",
+            TokenScope::Account => "account",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "search" => Some(TokenScope::Search),
+            "account" => Some(TokenScope::Account),
+            _ => None,
+        }
+    }
+}
+
+/// Tokens start with this, so leaked ones are easy to spot
+const TOKEN_PREFIX: &str = "bang_";
+/// Longest expiry a token can be created with, about ten years
+pub const MAX_EXPIRY_DAYS: i64 = 3650;
+
+/// A token as shown to its owner, without the secret.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    /// `None` grants every scope
+    pub scopes: Option<Vec<TokenScope>>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn decode_scopes(scopes: Option<String>) -> Option<Vec<TokenScope>> {
+    scopes.map(|scopes| {
+        scopes
+            .split(',')
+            .filter_map(TokenScope::parse)
+            .collect()
+    })
+}
+
+/// Personal access tokens for clients that can't keep the session cookie, stored hashed.
+pub struct TokenService {
+    pool: SqlitePool,
+}
+
+impl TokenService {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Create a token, the returned secret isn't stored and can't be shown again.
+    pub async fn create(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: Option<&[TokenScope]>,
+        expires_in_days: Option<i64>,
+    ) -> Result<(ApiToken, String), TokenError> {
+        let random: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect();
+        let secret = format!("{TOKEN_PREFIX}{random}");
+        let token_hash = hash_token(&secret);
+        let prefix = secret[..TOKEN_PREFIX.len() + 6].to_string();
+        let scopes = scopes.map(|scopes| {
+            scopes
+                .iter()
+                .map(TokenScope::as_str)
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let created_at = Utc::now().naive_utc();
+        let expires_at = expires_in_days.map(|days| created_at + Duration::days(days));
+
+        let id = sqlx::query!(
+            "INSERT INTO api_tokens (user_id, name, token_hash, prefix, scopes, created_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            user_id,
+            name,
+            token_hash,
+            prefix,
+            scopes,
+            created_at,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?
+        .last_insert_rowid();
+
+        let token = ApiToken {
+            id,
+            name: name.to_string(),
+            prefix,
+            scopes: decode_scopes(scopes),
+            created_at,
+            expires_at,
+            last_used_at: None,
+        };
+        Ok((token, secret))
+    }
+
+    pub async fn list(&self, user_id: i64) -> Result<Vec<ApiToken>, TokenError> {
+        let rows = sqlx::query!(
+            r#"SELECT id AS "id!: i64", name, prefix, scopes, created_at AS "created_at!: NaiveDateTime",
+                      expires_at, last_used_at
+               FROM api_tokens
+               WHERE user_id = ?
+               ORDER BY created_at DESC"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| ApiToken {
+                id: row.id,
+                name: row.name,
+                prefix: row.prefix,
+                scopes: decode_scopes(row.scopes),
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+            })
+            .collect())
+    }
+
+    /// Delete one of the user's tokens. Returns whether it existed.
+    pub async fn revoke(&self, user_id: i64, id: i64) -> Result<bool, TokenError> {
+        let removed = sqlx::query!("DELETE FROM api_tokens WHERE id = ? AND user_id = ?", id, user_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(removed > 0)
+    }
+
+    /// The user a token belongs to, if it's valid and grants `scope`.
+    pub async fn authenticate(&self, secret: &str, scope: TokenScope) -> Result<i64, TokenError> {
+        let now = Utc::now().naive_utc();
+        let token_hash = hash_token(secret);
+        let token = sqlx::query!(
+            "SELECT id, user_id, scopes FROM api_tokens
+             WHERE token_hash = ? AND (expires_at IS NULL OR expires_at > ?)",
+            token_hash,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(TokenError::Invalid)?;
+
+        if let Some(scopes) = decode_scopes(token.scopes)
+            && !scopes.contains(&scope)
+        {
+            return Err(TokenError::MissingScope(scope.as_str()));
+        }
+
+        sqlx::query!("UPDATE api_tokens SET last_used_at = ? WHERE id = ?", now, token.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(token.user_id)
+    }
+}
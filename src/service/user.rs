@@ -0,0 +1,315 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    /// Larger daily quota
+    Power,
+    /// Unlimited quota and cache administration
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Power => "power",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "user" => Ok(Role::User),
+            "power" => Ok(Role::Power),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("Unknown role {role}, expected user, power or admin")),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+    pub banned: bool,
+    /// Not a member of the required Discord guilds anymore
+    pub suspended: bool,
+}
+
+impl User {
+    /// Why the user may not sign in or search, if blocked.
+    pub fn blocked_reason(&self) -> Option<&'static str> {
+        if self.banned {
+            Some("This account is banned")
+        } else if self.suspended {
+            Some("This account is suspended until you rejoin the community's Discord server")
+        } else {
+            None
+        }
+    }
+}
+
+/// A login identity as included in a data export.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct IdentityExport {
+    pub provider: String,
+    pub subject: String,
+    pub username: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Everything stored in the users and identities tables about a user.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct UserExport {
+    #[serde(flatten)]
+    pub user: User,
+    pub request_count: i64,
+    pub cached_request_count: i64,
+    pub last_request_date: NaiveDate,
+    pub created_at: Option<NaiveDateTime>,
+    pub identities: Vec<IdentityExport>,
+}
+
+/// Roles, bans and the login allowlist.
+pub struct UserService {
+    pool: SqlitePool,
+    /// Only identities on the allowlist may sign up
+    allowlist_mode: bool,
+    /// Roles from `POWER_USER_IDS` and `ADMIN_USER_IDS`, they win over roles set any other way
+    configured_roles: HashMap<i64, Role>,
+}
+
+impl UserService {
+    pub fn new(pool: SqlitePool, allowlist_mode: bool, configured_roles: HashMap<i64, Role>) -> Self {
+        Self {
+            pool,
+            allowlist_mode,
+            configured_roles,
+        }
+    }
+
+    pub fn configured_role(&self, user_id: i64) -> Option<Role> {
+        self.configured_roles.get(&user_id).copied()
+    }
+
+    /// Give a user their configured role back, if they have one.
+    pub async fn restore_configured_role(&self, user_id: i64) -> Result<(), sqlx::Error> {
+        if let Some(role) = self.configured_role(user_id) {
+            self.set_role(user_id, role).await?;
+        }
+        Ok(())
+    }
+
+    /// Give every configured user their role back, returns the ids of users that don't exist.
+    pub async fn apply_configured_roles(&self) -> Result<Vec<i64>, sqlx::Error> {
+        let mut missing = Vec::new();
+        for (&user_id, &role) in &self.configured_roles {
+            if !self.set_role(user_id, role).await? {
+                missing.push(user_id);
+            }
+        }
+        Ok(missing)
+    }
+
+    pub async fn get(&self, user_id: i64) -> Result<Option<User>, sqlx::Error> {
+        let user = sqlx::query!(
+            r#"SELECT id AS "id!: i64", username, role, banned, suspended FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user.map(|user| User {
+            id: user.id,
+            username: user.username,
+            role: user.role.parse().unwrap_or_default(),
+            banned: user.banned,
+            suspended: user.suspended,
+        }))
+    }
+
+    pub async fn list(&self) -> Result<Vec<User>, sqlx::Error> {
+        let users = sqlx::query!(r#"SELECT id AS "id!: i64", username, role, banned, suspended FROM users ORDER BY id"#)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(users
+            .into_iter()
+            .map(|user| User {
+                id: user.id,
+                username: user.username,
+                role: user.role.parse().unwrap_or_default(),
+                banned: user.banned,
+                suspended: user.suspended,
+            })
+            .collect())
+    }
+
+    /// Returns whether the user exists.
+    pub async fn set_role(&self, user_id: i64, role: Role) -> Result<bool, sqlx::Error> {
+        let role = role.as_str();
+        // A role set by hand is no longer taken back by the guild role mapping
+        let updated = sqlx::query!("UPDATE users SET role = ?, guild_role = NULL WHERE id = ?", role, user_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(updated > 0)
+    }
+
+    /// Returns whether the user exists.
+    pub async fn set_banned(&self, user_id: i64, banned: bool) -> Result<bool, sqlx::Error> {
+        let updated = sqlx::query!("UPDATE users SET banned = ? WHERE id = ?", banned, user_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(updated > 0)
+    }
+
+    pub async fn export(&self, user_id: i64) -> Result<Option<UserExport>, sqlx::Error> {
+        let Some(user) = sqlx::query!(
+            r#"SELECT id AS "id!: i64", username, role, banned, suspended,
+                request_count, cached_request_count, last_request_date, created_at AS "created_at: NaiveDateTime"
+               FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let identities = sqlx::query_as!(
+            IdentityExport,
+            "SELECT provider, subject, username, created_at FROM identities WHERE user_id = ? ORDER BY created_at",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Some(UserExport {
+            user: User {
+                id: user.id,
+                username: user.username,
+                role: user.role.parse().unwrap_or_default(),
+                banned: user.banned,
+                suspended: user.suspended,
+            },
+            request_count: user.request_count,
+            cached_request_count: user.cached_request_count,
+            last_request_date: user.last_request_date,
+            created_at: user.created_at,
+            identities,
+        }))
+    }
+
+    /// When the user signed up, `None` for users that signed up before it was recorded.
+    pub async fn created_at(&self, user_id: i64) -> Result<Option<NaiveDateTime>, sqlx::Error> {
+        let created_at = sqlx::query_scalar!(
+            r#"SELECT created_at AS "created_at: NaiveDateTime" FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(created_at.flatten())
+    }
+
+    /// Delete a user with their identities, password, tokens, sessions and allowlist entries.
+    /// Cache entries aren't linked to users, so nothing there identifies them. Returns whether the user existed.
+    pub async fn delete(&self, user_id: i64) -> Result<bool, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query!(
+            "DELETE FROM login_allowlist WHERE (provider, subject) IN
+                (SELECT provider, subject FROM identities WHERE user_id = ?)",
+            user_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query!("DELETE FROM local_accounts WHERE user_id = ?", user_id)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query!("DELETE FROM api_tokens WHERE user_id = ?", user_id)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query!("DELETE FROM identities WHERE user_id = ?", user_id)
+            .execute(&mut *transaction)
+            .await?;
+        let removed = sqlx::query!("DELETE FROM users WHERE id = ?", user_id)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+        transaction.commit().await?;
+        Ok(removed > 0)
+    }
+
+    /// Whether a new identity may sign up.
+    pub async fn is_allowed(&self, provider: &str, subject: &str) -> Result<bool, sqlx::Error> {
+        if !self.allowlist_mode {
+            return Ok(true);
+        }
+        let allowed = sqlx::query_scalar!(
+            r#"SELECT EXISTS(
+                SELECT 1 FROM login_allowlist WHERE provider = ? AND subject = ?
+                UNION ALL
+                SELECT 1 FROM identities WHERE provider = ? AND subject = ?
+            ) AS "allowed!: bool""#,
+            provider,
+            subject,
+            provider,
+            subject
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(allowed)
+    }
+
+    pub async fn allow(&self, provider: &str, subject: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO login_allowlist (provider, subject) VALUES (?, ?) ON CONFLICT DO NOTHING",
+            provider,
+            subject
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns whether the identity was on the allowlist.
+    pub async fn disallow(&self, provider: &str, subject: &str) -> Result<bool, sqlx::Error> {
+        let removed = sqlx::query!(
+            "DELETE FROM login_allowlist WHERE provider = ? AND subject = ?",
+            provider,
+            subject
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(removed > 0)
+    }
+
+    pub async fn allowlist(&self) -> Result<Vec<(String, String)>, sqlx::Error> {
+        let entries = sqlx::query!("SELECT provider, subject FROM login_allowlist ORDER BY created_at")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| (entry.provider, entry.subject))
+            .collect())
+    }
+}
@@ -0,0 +1,47 @@
+// Renders the captcha widget configured at /auth/config and reports its token.
+// `captcha` is the `captcha` object of /auth/config, `action` must match the action the server checks.
+function renderCaptcha(container, captcha, action, onToken) {
+    const loadScript = (src) => {
+        const script = document.createElement('script');
+        script.src = src;
+        script.async = true;
+        document.head.appendChild(script);
+    };
+
+    switch (captcha.provider) {
+        case 'turnstile':
+            window.onloadCaptcha = () => {
+                turnstile.render(container, {
+                    sitekey: captcha.site_key,
+                    action,
+                    // The token is submitted as `captcha-response` by the page
+                    'response-field': false,
+                    callback: onToken,
+                });
+            };
+            loadScript('https://challenges.cloudflare.com/turnstile/v0/api.js?render=explicit&onload=onloadCaptcha');
+            break;
+        case 'hcaptcha':
+            window.onloadCaptcha = () => {
+                hcaptcha.render(document.querySelector(container), {
+                    sitekey: captcha.site_key,
+                    callback: onToken,
+                });
+            };
+            loadScript('https://js.hcaptcha.com/1/api.js?render=explicit&onload=onloadCaptcha');
+            break;
+        case 'recaptcha':
+            // reCAPTCHA v3 is invisible, tokens expire after two minutes so a fresh one is fetched regularly
+            window.onloadCaptcha = () => {
+                const execute = () => grecaptcha.execute(captcha.site_key, { action }).then(onToken);
+                grecaptcha.ready(() => {
+                    execute();
+                    setInterval(execute, 90 * 1000);
+                });
+            };
+            loadScript(`https://www.google.com/recaptcha/api.js?render=${captcha.site_key}&onload=onloadCaptcha`);
+            break;
+        default:
+            onToken('');
+    }
+}
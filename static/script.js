@@ -47,8 +47,14 @@ document.addEventListener('DOMContentLoaded', () => {
             throw new Error('Not logged in');
         })
         .then(user => {
+            const formatCounter = (counter) => counter.limit === null
+                ? `${counter.used}`
+                : `${counter.used}/${counter.limit}`;
+            const quota = `${formatCounter(user.quota.fresh)} searches, ${formatCounter(user.quota.cached)} cached today`;
             const userHtml = `
                 <span>Welcome, ${user.username}</span>
+                <span class="quota" title="Cached searches don't count against the search limit">${quota}</span>
+                <a href="/settings.html">Settings</a>
                 <a href="/auth/logout">Logout</a>
             `;
             userStatusDiv.insertAdjacentHTML('afterbegin', userHtml);